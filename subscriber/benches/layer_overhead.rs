@@ -0,0 +1,180 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::Dispatch;
+use tracing_subscriber::prelude::*;
+
+use coruscant_subscriber::bernoulli::CategoryBernEstimator;
+use coruscant_subscriber::bernoulli::ManyCategoryBernEstimator;
+use coruscant_subscriber::dependency::DependencyLayer;
+use coruscant_subscriber::markov::ContextMarkovEstimator;
+use coruscant_subscriber::normal::ManyCategoryNormalEstimator;
+
+
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+const STATES: [&str; 4] = ["call_a", "call_b", "call_c", "call_d"];
+
+/* Subscribers compared: the bare registry is the floor any layer adds to */
+fn dispatches() -> Vec<(&'static str, Dispatch)> {
+    let (dep_layer, _dep_processor) = DependencyLayer::construct();
+    vec![
+        ("registry", Dispatch::new(tracing_subscriber::Registry::default())),
+        ("dependency_layer", Dispatch::new(tracing_subscriber::Registry::default().with(dep_layer))),
+    ]
+}
+
+/* on_new_span and on_close of a root span, whose parent keeps no history */
+fn bench_span(c: &mut Criterion) {
+    let mut group = c.benchmark_group("span");
+    for (name, dispatch) in dispatches() {
+        tracing::dispatcher::with_default(&dispatch, || {
+            group.bench_function(name, |b| b.iter(|| {
+                tracing::info_span!("child", n = 1).in_scope(|| {})
+            }));
+        });
+    }
+    group.finish();
+}
+
+/* on_event of an error marking the current span failing */
+fn bench_error_event(c: &mut Criterion) {
+    let mut group = c.benchmark_group("error_event");
+    for (name, dispatch) in dispatches() {
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _parent = tracing::info_span!("parent").entered();
+            group.bench_function(name, |b| b.iter(|| {
+                tracing::error!("failed")
+            }));
+        });
+    }
+    group.finish();
+}
+
+/* Root spans from many threads at once */
+fn bench_span_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("span_contention");
+    for (name, dispatch) in dispatches() {
+        for threads in THREADS {
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| run_threads(&dispatch, threads, iters))
+            });
+        }
+    }
+    group.finish();
+}
+
+/* Wall time for `threads` threads to create and close `iters` spans each,
+   reported per span of one thread */
+fn run_threads(dispatch: &Dispatch, threads: usize, iters: u64) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| tracing::dispatcher::with_default(dispatch, || {
+                for _ in 0..iters {
+                    tracing::info_span!("child").in_scope(|| {});
+                }
+            }));
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_estimators(c: &mut Criterion) {
+    let mut group = c.benchmark_group("estimator_observe");
+    let states: Vec<String> = STATES.iter().map(|state| state.to_string()).collect();
+    let state = |idx: usize| states[idx % states.len()].clone();
+
+    let markov = ContextMarkovEstimator::default();
+    group.bench_function("context_markov", |b| {
+        let mut idx = 0;
+        b.iter(|| {
+            idx += 1;
+            markov.observe(state(idx), state(idx + 1), state(idx + 2))
+        })
+    });
+
+    let category_bern = CategoryBernEstimator::default();
+    group.bench_function("category_bern", |b| {
+        let mut idx = 0;
+        b.iter(|| {
+            idx += 1;
+            category_bern.observe_present(state(idx))
+        })
+    });
+
+    let many_category_bern = ManyCategoryBernEstimator::<String>::default();
+    group.bench_function("many_category_bern", |b| {
+        let mut idx = 0;
+        b.iter(|| {
+            idx += 1;
+            many_category_bern.observe_absent(state(idx), state(idx + 1))
+        })
+    });
+
+    let many_category_normal = ManyCategoryNormalEstimator::default();
+    group.bench_function("many_category_normal", |b| {
+        let mut idx = 0;
+        b.iter(|| {
+            idx += 1;
+            many_category_normal.observe(state(idx), state(idx + 1), idx as f64)
+        })
+    });
+    group.finish();
+}
+
+/* observe on one shared estimator from many threads */
+fn bench_estimator_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("estimator_contention");
+    let markov = ContextMarkovEstimator::default();
+    for threads in THREADS {
+        group.bench_with_input(BenchmarkId::new("context_markov", threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                std::thread::scope(|scope| {
+                    for thread in 0..threads {
+                        let markov = &markov;
+                        scope.spawn(move || {
+                            for idx in 0..iters as usize {
+                                markov.observe(
+                                    STATES[thread % STATES.len()].to_string(),
+                                    STATES[idx % STATES.len()].to_string(),
+                                    STATES[(idx + 1) % STATES.len()].to_string(),
+                                );
+                            }
+                        });
+                    }
+                });
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+/* Periodic summary of a populated estimator with a small delta since the last one */
+fn bench_summarize(c: &mut Criterion) {
+    let markov = ContextMarkovEstimator::default();
+    for context in 0..256 {
+        for state in 0..16 {
+            markov.observe(format!("context_{}", context), format!("state_{}", state), STATES[0].to_string());
+        }
+    }
+    c.bench_function("summarize/context_markov", |b| b.iter(|| {
+        markov.observe(STATES[0].to_string(), STATES[1].to_string(), STATES[2].to_string());
+        markov.summarize()
+    }));
+}
+
+criterion_group!(
+    benches,
+    bench_span,
+    bench_error_event,
+    bench_span_contention,
+    bench_estimators,
+    bench_estimator_contention,
+    bench_summarize,
+);
+criterion_main!(benches);
@@ -0,0 +1,173 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::measurement::Measurement;
+use criterion::measurement::ValueFormatter;
+use criterion::Criterion;
+use criterion::Throughput;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use tracing::Dispatch;
+use tracing_subscriber::prelude::*;
+
+use coruscant_subscriber::dependency::DependencyLayer;
+
+
+/* Counts allocations to report them per span */
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+
+const DEPTH: u32 = 6;
+const FANOUT: usize = 3;
+// (FANOUT^(DEPTH+1) - 1) / (FANOUT - 1) spans in the tree
+const SPANS_PER_TREE: usize = 1093;
+
+/* Nested chains of siblings, each closing with a chain of its own below */
+fn span_tree(depth: u32) {
+    let _span = tracing::info_span!("node", depth).entered();
+    if depth > 0 {
+        for _ in 0..FANOUT {
+            span_tree(depth - 1);
+        }
+    }
+}
+
+fn span_chain() {
+    span_tree(DEPTH);
+}
+
+fn dependency_dispatch() -> Dispatch {
+    let (dep_layer, _dep_processor) = DependencyLayer::construct();
+    Dispatch::new(tracing_subscriber::Registry::default().with(dep_layer))
+}
+
+fn bench_span_chain(c: &mut Criterion) {
+    let dispatch = dependency_dispatch();
+    let mut group = c.benchmark_group("span_chain");
+    group.throughput(Throughput::Elements(SPANS_PER_TREE as u64));
+    group.bench_function("dependency_layer", |b| {
+        tracing::dispatcher::with_default(&dispatch, || b.iter(span_chain))
+    });
+    group.finish();
+}
+
+/* Allocations or allocated bytes, counted instead of wall time */
+struct Allocated {
+    counter: &'static AtomicUsize,
+    unit: &'static str,
+    unit_per_span: &'static str,
+}
+
+const ALLOCATION_COUNT: Allocated = Allocated {
+    counter: &ALLOCATIONS,
+    unit: "allocs",
+    unit_per_span: "allocs/span",
+};
+
+const ALLOCATION_BYTES: Allocated = Allocated {
+    counter: &ALLOCATED_BYTES,
+    unit: "B",
+    unit_per_span: "B/span",
+};
+
+impl Measurement for Allocated {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        self.counter.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: usize) -> usize {
+        self.counter.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, lhs: &usize, rhs: &usize) -> usize {
+        lhs + rhs
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+impl ValueFormatter for Allocated {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        self.unit
+    }
+
+    fn scale_throughputs(&self, _typical_value: f64, throughput: &Throughput, values: &mut [f64]) -> &'static str {
+        let spans = match throughput {
+            Throughput::Elements(spans) => *spans as f64,
+            _ => return self.unit,
+        };
+        for value in values.iter_mut() {
+            *value /= spans;
+        }
+        self.unit_per_span
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        self.unit
+    }
+}
+
+/* Steady-state allocations per span, reported as throughput */
+fn bench_allocations(c: &mut Criterion<Allocated>, group_name: &str) {
+    let dispatch = dependency_dispatch();
+    let mut group = c.benchmark_group(group_name);
+    group.throughput(Throughput::Elements(SPANS_PER_TREE as u64));
+    group.bench_function("dependency_layer", |b| {
+        tracing::dispatcher::with_default(&dispatch, || b.iter(span_chain))
+    });
+    group.finish();
+}
+
+fn bench_allocation_count(c: &mut Criterion<Allocated>) {
+    bench_allocations(c, "span_chain_allocations")
+}
+
+fn bench_allocated_bytes(c: &mut Criterion<Allocated>) {
+    bench_allocations(c, "span_chain_allocated_bytes")
+}
+
+criterion_group!(benches, bench_span_chain);
+criterion_group! {
+    name = allocations;
+    config = Criterion::default().with_measurement(ALLOCATION_COUNT).without_plots();
+    targets = bench_allocation_count
+}
+criterion_group! {
+    name = allocated_bytes;
+    config = Criterion::default().with_measurement(ALLOCATION_BYTES).without_plots();
+    targets = bench_allocated_bytes
+}
+criterion_main!(benches, allocations, allocated_bytes);
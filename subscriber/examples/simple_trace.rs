@@ -13,6 +13,7 @@ struct DefaultError {
     why: String,
 }
 impl DefaultError {
+    #[allow(clippy::new_ret_no_self)]
     fn new(why: &str) -> GenericError {
         Box::new(DefaultError { why: why.to_string() })
     }
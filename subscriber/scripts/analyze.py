@@ -65,7 +65,7 @@ def dirichlet(count_s, count_f, total_s, total_f, steady_s, steady_f, mu):
 def coruscant_analyze(mchains, bernoullis, mu, verbose=0):
     all_inf_scores = dict()
     for span, mchain in mchains.items():
-        if span not in bernoullis or '__TOTAL__' not in bernoullis[span]:
+        if span not in bernoullis:
             # skip unseen failure
             continue
 
@@ -82,13 +82,12 @@ def coruscant_analyze(mchains, bernoullis, mu, verbose=0):
         for subspan in all_subspans:
             # retrieve bernoully
             bernoulli = bernoullis[span]
-            total_s = bernoulli['__TOTAL__'][1] - bernoulli['__TOTAL__'][0]
-            total_f = bernoulli['__TOTAL__'][0]
+            total_s = bernoulli['total'][1] - bernoulli['total'][0]
+            total_f = bernoulli['total'][0]
 
             # recover counts conditioned on failing span
             count_s, count_f = 0, 0
-            for failing_subspans, (failing_then_F, total) in bernoulli.items():
-                failing_subspans = failing_subspans.split(", ")
+            for failing_subspans, (failing_then_F, total) in bernoulli['failing_subspans']:
                 if subspan in failing_subspans:
                     count_s += total - failing_then_F
                     count_f += failing_then_F
@@ -119,9 +118,9 @@ def coruscant_analyze(mchains, bernoullis, mu, verbose=0):
 
 def nonzero(name, bernoullis, all_subspans):
     if name in bernoullis:
-        if bernoullis[name]["__TOTAL__"][0] > 0.0:
+        if bernoullis[name]["total"][0] > 0.0:
             return True
-    return any(bernoullis[n]["__TOTAL__"][0] > 0.0 for n in all_subspans if n in bernoullis)
+    return any(bernoullis[n]["total"][0] > 0.0 for n in all_subspans if n in bernoullis)
 
 
 def augment_name(name, bernoullis, label=None):
@@ -134,7 +133,7 @@ def draw_mc_dict(mchain, bernoullis, label_fn=lambda _: None):
     all_subspans = get_all_subspans(mchain)
     for subspan in all_subspans:
         if nonzero(subspan, bernoullis, []):
-            prob = bernoullis[subspan]["__TOTAL__"][0] / bernoullis[subspan]["__TOTAL__"][1]
+            prob = bernoullis[subspan]["total"][0] / bernoullis[subspan]["total"][1]
             print(f"\t{subspan}: {prob:.2e}")
 
     # reconstruct grap
@@ -230,7 +229,7 @@ if __name__ == '__main__':
         span_list = bernoullis.keys() if len(args.spans) == 0 else args.spans
         for span in span_list:
             if span in bernoullis:
-                prob_err = bernoullis[span]['__TOTAL__'][0] / bernoullis[span]['__TOTAL__'][1]
+                prob_err = bernoullis[span]['total'][0] / bernoullis[span]['total'][1]
             else:
                 prob_err = 0.0
             if len(args.spans) == 0 and prob_err <= 0.0: continue
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use crate::fanout::group_members;
+use crate::fanout::FanOutSummary;
+use crate::markov::MarkovSummary;
+use crate::markov::last_state;
+use crate::processor::FailBernoulliSummary;
+use crate::processor::ABANDONED_STATE;
+use crate::processor::FAILURE_STATE;
+use crate::processor::INITIAL_STATE;
+use crate::processor::SUCCESS_STATE;
+
+
+const ABSORPTION_ITERATIONS: usize = 4096;
+const ABSORPTION_TOLERANCE: f64 = 1e-12;
+
+
+/* Helpers over summarized chains, shared by the analyses */
+
+/// Whether a state is a bookkeeping state rather than a span
+pub fn is_special_state(state: &str) -> bool {
+    state == INITIAL_STATE || state == SUCCESS_STATE || state == FAILURE_STATE || state == ABANDONED_STATE
+}
+
+/// Every state appearing in a chain, either as a source or as a destination
+pub fn all_states(chain: &MarkovSummary<String>) -> BTreeSet<String> {
+    chain.iter()
+        .flat_map(|(state, edges)| std::iter::once(state).chain(edges.keys()))
+        .cloned()
+        .collect()
+}
+
+/// Spans a state stands for: the most recent one of a history state, and
+/// every member of a fan-out group
+pub fn state_spans(state: &str) -> Vec<&str> {
+    group_members(last_state(state))
+}
+
+/// Child spans appearing in a chain, without bookkeeping states
+pub fn child_spans(chain: &MarkovSummary<String>) -> BTreeSet<String> {
+    all_states(chain).iter()
+        .filter(|state| !is_special_state(state))
+        .flat_map(|state| state_spans(state))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Expected calls of every child span, from expected visits to the states
+/// of its parent's chain and how many children each fan-out group runs
+pub fn span_visits(
+    visits: BTreeMap<String, f64>,
+    fan_out: Option<&BTreeMap<String, FanOutSummary>>,
+) -> BTreeMap<String, f64> {
+    let mut span_visits = BTreeMap::new();
+    for (state, count) in visits {
+        let group = last_state(&state);
+        for span in group_members(group) {
+            let per_visit = fan_out
+                .and_then(|fan_out| fan_out.get(group))
+                .and_then(|fan_out| fan_out.members.get(span))
+                .map(|(mean, _, _)| *mean)
+                .unwrap_or(1.0);
+            *span_visits.entry(span.to_string()).or_insert(0.0) += count * per_visit;
+        }
+    }
+    span_visits
+}
+
+/// Probability of eventually reaching `sink` from every state of a chain
+///
+/// States without outgoing transitions other than `sink` are treated as
+/// absorbing, the same way analyze.py fills in sinks before exponentiating.
+pub fn absorption_probs(chain: &MarkovSummary<String>, sink: &str) -> BTreeMap<String, f64> {
+    let mut probs: BTreeMap<String, f64> = all_states(chain).into_iter()
+        .map(|state| {
+            let prob = if state == sink { 1.0 } else { 0.0 };
+            (state, prob)
+        })
+        .collect();
+    for _ in 0 .. ABSORPTION_ITERATIONS {
+        let mut delta: f64 = 0.0;
+        for (state, edges) in chain.iter() {
+            if state == sink {
+                continue;
+            }
+            let prob: f64 = edges.iter()
+                .map(|(next_state, transition)| transition * probs[next_state])
+                .sum();
+            delta = delta.max((prob - probs[state]).abs());
+            probs.insert(state.clone(), prob);
+        }
+        if delta < ABSORPTION_TOLERANCE {
+            break;
+        }
+    }
+    probs
+}
+
+/// Expected number of visits to every state of a chain starting from `start`
+///
+/// Only meaningful for absorbing chains; visits stop being counted once the
+/// iteration budget runs out.
+pub fn expected_visits(chain: &MarkovSummary<String>, start: &str) -> BTreeMap<String, f64> {
+    let states = all_states(chain);
+    let mut visits: BTreeMap<String, f64> = states.iter()
+        .map(|state| (state.clone(), 0.0))
+        .collect();
+    for _ in 0 .. ABSORPTION_ITERATIONS {
+        let mut delta: f64 = 0.0;
+        for state in states.iter() {
+            let mut count = if state == start { 1.0 } else { 0.0 };
+            for (prev_state, edges) in chain.iter() {
+                if let Some(transition) = edges.get(state) {
+                    count += visits[prev_state] * transition;
+                }
+            }
+            delta = delta.max((count - visits[state]).abs());
+            visits.insert(state.clone(), count);
+        }
+        if delta < ABSORPTION_TOLERANCE {
+            break;
+        }
+    }
+    visits
+}
+
+/// Observed failure rate of a span, if it has ever closed
+pub fn fail_rate(fail_bernoulli: &FailBernoulliSummary, span: &str) -> Option<f64> {
+    fail_bernoulli.get(span)
+        .map(|fail_summary| fail_summary.total)
+        .filter(|(_, total)| *total > 0)
+        .map(|(fails, total)| fails as f64 / total as f64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(edges: &[(&str, &str, f64)]) -> MarkovSummary<String> {
+        let mut chain = MarkovSummary::new();
+        for (state, next_state, prob) in edges {
+            chain.entry(state.to_string()).or_insert_with(BTreeMap::new).insert(next_state.to_string(), *prob);
+        }
+        chain
+    }
+
+    #[test]
+    fn absorption_probs_of_three_states() {
+        // call_b retries itself a third of the time, then fails half the time
+        let chain = chain(&[
+            (INITIAL_STATE, "call_b", 1.0),
+            ("call_b", "call_b", 1.0 / 3.0),
+            ("call_b", FAILURE_STATE, 1.0 / 3.0),
+            ("call_b", SUCCESS_STATE, 1.0 / 3.0),
+        ]);
+        let probs = absorption_probs(&chain, FAILURE_STATE);
+        assert!((probs[INITIAL_STATE] - 0.5).abs() < 1e-9);
+        assert!((probs["call_b"] - 0.5).abs() < 1e-9);
+        assert_eq!(probs[FAILURE_STATE], 1.0);
+        assert_eq!(probs[SUCCESS_STATE], 0.0);
+    }
+
+    #[test]
+    fn expected_visits_count_retries() {
+        let chain = chain(&[
+            (INITIAL_STATE, "call_b", 1.0),
+            ("call_b", "call_b", 0.5),
+            ("call_b", SUCCESS_STATE, 0.5),
+        ]);
+        let visits = expected_visits(&chain, INITIAL_STATE);
+        assert!((visits["call_b"] - 2.0).abs() < 1e-9);
+        assert!((visits[SUCCESS_STATE] - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn state_spans_of_groups_and_histories() {
+        assert_eq!(state_spans("[call_b & call_c]"), vec!["call_b", "call_c"]);
+        assert_eq!(state_spans("call_c > call_d"), vec!["call_d"]);
+        assert_eq!(state_spans("call_d"), vec!["call_d"]);
+    }
+}
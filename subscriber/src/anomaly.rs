@@ -0,0 +1,415 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use crate::bernoulli::BernSummary;
+use crate::processor::DependencySummary;
+use crate::record::ROOT_SPAN;
+use crate::stats;
+
+
+/*
+ * Anomalies between windows of the summary time series
+ *
+ *  Summaries are cumulative, so a window is the difference of two of them.
+ *  A process restart shows up as counts going backwards, in which case the
+ *  later summary alone is the window. Windows hold the recorded samples
+ *  rather than sampling-weighted counts, since the tests need raw counts.
+ *  Fail rates of the current window are tested against the baseline rate
+ *  with a binomial test, and the next states out of every chain state with
+ *  a chi-square homogeneity test. Every window runs many tests, so their
+ *  p-values are adjusted with the Benjamini-Hochberg procedure.
+ */
+
+/// Transitions per parent span, source state and destination state
+pub type TransitionCounts = BTreeMap<String, BTreeMap<String, BTreeMap<String, f64>>>;
+
+
+/// Samples recorded over a stretch of time
+#[derive(Clone, Debug, Default)]
+pub struct Window {
+    /// Failures and executions recorded per span
+    pub fail_counts: BTreeMap<String, BernSummary>,
+    pub transitions: TransitionCounts,
+}
+
+impl Window {
+    /// Everything recorded up to a summary
+    ///
+    /// Transitions out of a state split its samples by the weighted
+    /// probabilities, summaries without samples fall back to their counts.
+    pub fn from_summary(summary: &DependencySummary) -> Window {
+        let fail_counts = summary.fail_bernoulli.iter()
+            .map(|(span, fail_summary)| (span.clone(), fail_summary.samples))
+            .collect();
+        let mut transitions = TransitionCounts::new();
+        for (context, chain) in summary.span_markov.iter() {
+            let counts = summary.state_samples.get(context).or_else(|| summary.state_counts.get(context));
+            for (state, edges) in chain.iter() {
+                let count = counts
+                    .and_then(|counts| counts.get(state))
+                    .copied()
+                    .unwrap_or(0) as f64;
+                if count <= 0.0 {
+                    continue;
+                }
+                let next_counts = edges.iter()
+                    .map(|(next_state, prob)| (next_state.clone(), (prob * count).round()))
+                    .collect();
+                transitions.entry(context.clone()).or_default().insert(state.clone(), next_counts);
+            }
+        }
+        Window { fail_counts, transitions }
+    }
+
+    /// Everything recorded after `earlier` up to `later`
+    pub fn between(earlier: &DependencySummary, later: &DependencySummary) -> Window {
+        let earlier = Window::from_summary(earlier);
+        let mut window = Window::from_summary(later);
+        for (span, (fails, total)) in window.fail_counts.iter_mut() {
+            match earlier.fail_counts.get(span) {
+                Some((earlier_fails, earlier_total)) if earlier_total <= total => {
+                    *fails = fails.saturating_sub(*earlier_fails);
+                    *total -= earlier_total;
+                },
+                _ => {},
+            }
+        }
+        for (context, states) in window.transitions.iter_mut() {
+            for (state, next_counts) in states.iter_mut() {
+                let earlier_counts = match earlier.transitions.get(context).and_then(|states| states.get(state)) {
+                    Some(earlier_counts) => earlier_counts,
+                    None => continue,
+                };
+                if earlier_counts.values().sum::<f64>() > next_counts.values().sum::<f64>() {
+                    continue;
+                }
+                for (next_state, count) in next_counts.iter_mut() {
+                    *count = (*count - earlier_counts.get(next_state).copied().unwrap_or(0.0)).max(0.0);
+                }
+            }
+        }
+        window
+    }
+}
+
+
+/// What shifted between the baseline and the current window
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertKind {
+    FailRate,
+    Transition,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub span: String,
+    /// Source state of the shifted transitions
+    pub state: Option<String>,
+    /// Destination whose probability moved the most
+    pub next_state: Option<String>,
+    /// Fail rate, or probability of `next_state`, over the baseline
+    pub baseline: f64,
+    /// Same over the current window
+    pub current: f64,
+    /// Observations in the current window
+    pub count: usize,
+    pub p_value: f64,
+    /// Benjamini-Hochberg adjusted over every test of the window
+    pub adjusted_p_value: f64,
+}
+
+impl Alert {
+    /// One-line description naming the span or edge
+    pub fn describe(&self) -> String {
+        let subject = match (&self.state, &self.next_state) {
+            (Some(state), Some(next_state)) => format!("{} [ {} --> {} ]", self.span, state, next_state),
+            _ => self.span.clone(),
+        };
+        let what = match self.kind {
+            AlertKind::FailRate => "fail rate",
+            AlertKind::Transition => "transition",
+        };
+        format!(
+            "{} {} {:.2}% -> {:.2}% over {} observations (p = {:.2e}, adjusted {:.2e})",
+            subject,
+            what,
+            100.0 * self.baseline,
+            100.0 * self.current,
+            self.count,
+            self.p_value,
+            self.adjusted_p_value,
+        )
+    }
+}
+
+
+/// Flags spans and edges whose statistics shifted against a baseline
+#[derive(Clone, Copy, Debug)]
+pub struct AnomalyDetector {
+    /// False discovery rate, adjusted p-value under which a test raises an alert
+    pub significance: f64,
+    /// Observations needed in both windows before testing
+    pub min_count: usize,
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> Self {
+        AnomalyDetector {
+            significance: stats::DEFAULT_SIGNIFICANCE,
+            min_count: 30,
+        }
+    }
+}
+
+impl AnomalyDetector {
+    pub fn with_significance(mut self, significance: f64) -> Self {
+        self.significance = significance;
+        self
+    }
+
+    pub fn with_min_count(mut self, min_count: usize) -> Self {
+        self.min_count = min_count;
+        self
+    }
+
+    /// Alerts of the latest window of a summary history, against the
+    /// `baseline_windows` windows before it, or everything before it if none
+    pub fn detect_latest(&self, history: &[DependencySummary], baseline_windows: Option<usize>) -> Vec<Alert> {
+        let (previous, latest) = match history {
+            [.., previous, latest] => (previous, latest),
+            _ => return Vec::new(),
+        };
+        let current = Window::between(previous, latest);
+        let previous_idx = history.len() - 2;
+        let baseline = match baseline_windows {
+            Some(windows) if windows < previous_idx => Window::between(&history[previous_idx - windows], previous),
+            _ => Window::from_summary(previous),
+        };
+        self.detect(&baseline, &current)
+    }
+
+    /// Alerts of the current window against the baseline, most significant first
+    pub fn detect(&self, baseline: &Window, current: &Window) -> Vec<Alert> {
+        // every test goes in, to adjust over all of them
+        let mut alerts = Vec::new();
+        for (span, (fails, total)) in current.fail_counts.iter() {
+            let (baseline_fails, baseline_total) = match baseline.fail_counts.get(span) {
+                Some(counts) => *counts,
+                None => continue,
+            };
+            if *total < self.min_count || baseline_total < self.min_count {
+                continue;
+            }
+            let baseline_rate = baseline_fails as f64 / baseline_total as f64;
+            let p_value = stats::binomial_test(*fails, *total, baseline_rate);
+            alerts.push(Alert {
+                kind: AlertKind::FailRate,
+                span: span.clone(),
+                state: None,
+                next_state: None,
+                baseline: baseline_rate,
+                current: *fails as f64 / *total as f64,
+                count: *total,
+                p_value,
+                adjusted_p_value: p_value,
+            });
+        }
+
+        for (context, states) in current.transitions.iter() {
+            if context == ROOT_SPAN {
+                // root spans interleave across requests, their order means little
+                continue;
+            }
+            for (state, next_counts) in states.iter() {
+                let baseline_counts = match baseline.transitions.get(context).and_then(|states| states.get(state)) {
+                    Some(baseline_counts) => baseline_counts,
+                    None => continue,
+                };
+                if let Some(alert) = self.test_transitions(context, state, baseline_counts, next_counts) {
+                    alerts.push(alert);
+                }
+            }
+        }
+        let p_values: Vec<f64> = alerts.iter().map(|alert| alert.p_value).collect();
+        for (alert, adjusted) in alerts.iter_mut().zip(stats::benjamini_hochberg(&p_values)) {
+            alert.adjusted_p_value = adjusted;
+        }
+        alerts.retain(|alert| alert.adjusted_p_value < self.significance);
+        alerts.sort_by(|a, b| a.p_value.total_cmp(&b.p_value).then(a.span.cmp(&b.span)));
+        alerts
+    }
+
+    fn test_transitions(
+        &self,
+        context: &str,
+        state: &str,
+        baseline_counts: &BTreeMap<String, f64>,
+        current_counts: &BTreeMap<String, f64>,
+    ) -> Option<Alert> {
+        let baseline_total: f64 = baseline_counts.values().sum();
+        let current_total: f64 = current_counts.values().sum();
+        if current_total < self.min_count as f64 || baseline_total < self.min_count as f64 {
+            return None;
+        }
+        let next_states: BTreeSet<&String> = baseline_counts.keys().chain(current_counts.keys()).collect();
+        let counts_of = |counts: &BTreeMap<String, f64>| -> Vec<f64> {
+            next_states.iter().map(|next_state| counts.get(*next_state).copied().unwrap_or(0.0)).collect()
+        };
+        let (baseline_vec, current_vec) = (counts_of(baseline_counts), counts_of(current_counts));
+        let (_, _, p_value) = stats::chi_square_homogeneity(&baseline_vec, &current_vec);
+        let (next_state, baseline_prob, current_prob) = next_states.iter()
+            .zip(baseline_vec.iter().zip(current_vec.iter()))
+            .map(|(next_state, (baseline, current))| (*next_state, baseline / baseline_total, current / current_total))
+            .max_by(|(_, lhs_base, lhs_cur), (_, rhs_base, rhs_cur)| {
+                (lhs_cur - lhs_base).abs().total_cmp(&(rhs_cur - rhs_base).abs())
+            })?;
+        Some(Alert {
+            kind: AlertKind::Transition,
+            span: context.to_string(),
+            state: Some(state.to_string()),
+            next_state: Some(next_state.clone()),
+            baseline: baseline_prob,
+            current: current_prob,
+            count: current_total.round() as usize,
+            p_value,
+            adjusted_p_value: p_value,
+        })
+    }
+}
+
+
+/// Follows a summary file as lines get appended to it
+///
+/// Only complete lines past the last read are parsed, and lines that fail to
+/// parse are skipped, so a torn or corrupt line does not stall the reader.
+#[derive(Clone, Debug)]
+pub struct SummaryTail {
+    path: String,
+    /// Bytes read so far, up to the end of the last complete line
+    offset: u64,
+}
+
+impl SummaryTail {
+    pub fn new(path: &str) -> SummaryTail {
+        SummaryTail {
+            path: path.to_string(),
+            offset: 0,
+        }
+    }
+
+    /// Summaries appended since the last read, and whether the file was
+    /// truncated in between, in which case they start from its beginning
+    pub fn read_appended(&mut self) -> std::io::Result<(Vec<DependencySummary>, bool)> {
+        let mut file = File::open(&self.path)?;
+        let truncated = file.metadata()?.len() < self.offset;
+        if truncated {
+            self.offset = 0;
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut appended = Vec::new();
+        file.read_to_end(&mut appended)?;
+        // a line without its newline may still be being written
+        let complete = match appended.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => &appended[..=end],
+            None => return Ok((Vec::new(), truncated)),
+        };
+        self.offset += complete.len() as u64;
+        let mut summaries = Vec::new();
+        for line in complete.split(|byte| *byte == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(summary) => summaries.push(summary),
+                Err(e) => log::warn!("Skipping unreadable summary line in {}: {}", self.path, e),
+            }
+        }
+        Ok((summaries, truncated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /* handle ran `executions` times and moved on from call_a to call_b or call_c */
+    fn summary(fails: usize, executions: usize, to_call_b: f64, samples: usize) -> DependencySummary {
+        serde_json::from_value(json!({
+            "span_markov": {"handle": {"call_a": {"call_b": to_call_b, "call_c": 1.0 - to_call_b}}},
+            "state_counts": {"handle": {"call_a": 10 * samples}},
+            "state_samples": {"handle": {"call_a": samples}},
+            "fail_bernoulli": {"handle": {
+                "total": [10 * fails, 10 * executions],
+                "failing_subspans": [],
+                "samples": [fails, executions],
+            }},
+        })).unwrap()
+    }
+
+    fn transitions(window: &Window) -> Vec<f64> {
+        window.transitions["handle"]["call_a"].values().copied().collect()
+    }
+
+    #[test]
+    fn windows_count_samples_rather_than_weights() {
+        let window = Window::from_summary(&summary(2, 10, 0.5, 10));
+        assert_eq!(window.fail_counts["handle"], (2, 10));
+        assert_eq!(transitions(&window), vec![5.0, 5.0]);
+    }
+
+    #[test]
+    fn window_between_subtracts_the_earlier_summary() {
+        let window = Window::between(&summary(2, 10, 0.5, 10), &summary(5, 30, 0.25, 40));
+        assert_eq!(window.fail_counts["handle"], (3, 20));
+        assert_eq!(transitions(&window), vec![5.0, 25.0]);
+        // counts going backwards mean a restart, the later summary is the window
+        let window = Window::between(&summary(5, 30, 0.25, 40), &summary(1, 4, 0.5, 4));
+        assert_eq!(window.fail_counts["handle"], (1, 4));
+        assert_eq!(transitions(&window), vec![2.0, 2.0]);
+    }
+
+    #[test]
+    fn alerts_adjust_over_every_test_of_the_window() {
+        let window = |spans: &[(&str, BernSummary)]| Window {
+            fail_counts: spans.iter().map(|(span, counts)| (span.to_string(), *counts)).collect(),
+            transitions: TransitionCounts::new(),
+        };
+        let detector = AnomalyDetector::default().with_significance(0.05);
+        // binomial_test(30, 100, 0.2) is about 0.0225
+        let alerts = detector.detect(&window(&[("call_a", (20, 100))]), &window(&[("call_a", (30, 100))]));
+        assert_eq!(alerts.len(), 1);
+        assert!((alerts[0].adjusted_p_value - alerts[0].p_value).abs() < 1e-12);
+        let steady = [("call_b", (20, 100)), ("call_c", (20, 100))];
+        let baseline = window(&[&[("call_a", (20, 100))], &steady[..]].concat());
+        let current = window(&[&[("call_a", (30, 100))], &steady[..]].concat());
+        assert!(detector.detect(&baseline, &current).is_empty());
+    }
+
+    #[test]
+    fn summary_tail_reads_complete_lines_and_skips_bad_ones() {
+        let path = std::env::temp_dir().join(format!("coruscant_tail_{}.jsons", std::process::id()));
+        let line = r#"{"span_markov":{},"fail_bernoulli":{}}"#;
+        std::fs::write(&path, format!("{}\nnot json\n{}", line, &line[..10])).unwrap();
+        let mut tail = SummaryTail::new(path.to_str().unwrap());
+        let (summaries, truncated) = tail.read_appended().unwrap();
+        assert_eq!((summaries.len(), truncated), (1, false));
+        // the torn line is read once it is complete
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, format!("{}\n", &line[10..]).as_bytes()).unwrap();
+        let (summaries, truncated) = tail.read_appended().unwrap();
+        assert_eq!((summaries.len(), truncated), (1, false));
+        assert_eq!(tail.read_appended().unwrap().0.len(), 0);
+        std::fs::write(&path, format!("{}\n", line)).unwrap();
+        let (summaries, truncated) = tail.read_appended().unwrap();
+        assert_eq!((summaries.len(), truncated), (1, true));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,105 +1,168 @@
 use std::collections::BTreeMap;
-use chashmap::CHashMap;
 use std::hash::Hash;
 
+use crate::estimator::Estimator;
+use crate::estimator::Keyed;
+use crate::shard::Sharded;
 
-type BernSummary = (usize, usize);
-type CategoryBernSummary<T> = BTreeMap<T, BernSummary>;
-pub type ManyCategoryBernSummary<T> = BTreeMap<T, CategoryBernSummary<T>>;
 
+pub type BernSummary = (usize, usize);
+pub type CategoryBernSummary<T> = BTreeMap<T, BernSummary>;
+pub type ManyCategoryBernSummary<T, S = T> = BTreeMap<T, CategoryBernSummary<S>>;
 
-/* Bernoulli */
+
+/* Bernoulli, observing whether the event is present
+ *
+ *  Counts are weighted, so under sampling they stand for 1/p executions
+ *  each. Significance tests need the number of executions actually seen,
+ *  kept apart as samples. */
 #[derive(Default, Clone, Debug)]
-struct BernEstimator {
-    total_count: usize,
-    event_count: usize,
+pub struct BernEstimator {
+    total_count: f64,
+    event_count: f64,
+    total_samples: usize,
+    event_samples: usize,
 }
 
 impl BernEstimator {
-    fn observe_absent(&mut self) {
-        self.total_count += 1;
+    /// Events and observations as recorded, regardless of their weights
+    pub fn samples(&self) -> BernSummary {
+        (self.event_samples, self.total_samples)
     }
+}
+
+impl Estimator for BernEstimator {
+    type Observation = bool;
+    type Summary = BernSummary;
 
-    fn observe_present(&mut self) {
-        self.total_count += 1;
-        self.event_count += 1;
+    fn observe(&mut self, present: bool, weight: f64) {
+        self.total_count += weight;
+        self.total_samples += 1;
+        if present {
+            self.event_count += weight;
+            self.event_samples += 1;
+        }
     }
 
-    fn summarize(self) -> BernSummary {
-        (self.event_count, self.total_count)
+    fn merge(&mut self, other: Self) {
+        self.total_count += other.total_count;
+        self.event_count += other.event_count;
+        self.total_samples += other.total_samples;
+        self.event_samples += other.event_samples;
+    }
+
+    fn summarize(&self) -> BernSummary {
+        (self.event_count.round() as usize, self.total_count.round() as usize)
     }
 }
 
 
-/* Multiple Bernoulli  */
-#[derive(Default, Clone, Debug)]
-pub struct CategoryBernEstimator<T> {
-    state_models: CHashMap<T, BernEstimator>
+/* Multiple Bernoulli, sharded per thread */
+#[derive(Debug)]
+pub struct CategoryBernEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
+{
+    state_models: Sharded<Keyed<T, BernEstimator>>
 }
 
-impl<T> CategoryBernEstimator<T> 
-where T: PartialEq + Eq + Hash + Ord + Default + std::fmt::Debug
+impl<T> Default for CategoryBernEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
 {
-    pub fn observe_absent(&self, state: T) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe_absent()
-        } else {
-            let mut bm = BernEstimator::default();
-            bm.observe_absent();
-            self.state_models.insert_new(state, bm)
+    fn default() -> Self {
+        CategoryBernEstimator {
+            state_models: Sharded::default(),
         }
     }
+}
+
+impl<T> CategoryBernEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
+{
+    pub fn observe_absent(&self, state: T) {
+        self.observe_absent_weighted(state, 1.0)
+    }
+
+    pub fn observe_absent_weighted(&self, state: T, weight: f64) {
+        self.state_models.observe((state, false), weight)
+    }
 
     pub fn observe_present(&self, state: T) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe_present()
-        } else {
-            let mut bm = BernEstimator::default();
-            bm.observe_present();
-            self.state_models.insert_new(state, bm)
-        }
+        self.observe_present_weighted(state, 1.0)
+    }
+
+    pub fn observe_present_weighted(&self, state: T, weight: f64) {
+        self.state_models.observe((state, true), weight)
+    }
+
+    /// Summary of everything observed so far, leaving the counts in place
+    pub fn summarize(&self) -> CategoryBernSummary<T> {
+        self.state_models.summarize()
+    }
+
+    /// Unweighted counts of every state, see `BernEstimator::samples`
+    pub fn summarize_samples(&self) -> CategoryBernSummary<T> {
+        self.state_models.with_total(|state_models| {
+            state_models.iter()
+                .map(|(state, model)| (state.clone(), model.samples()))
+                .collect()
+        })
     }
 
-    pub fn summarize(self) -> CategoryBernSummary<T> {
-        self.state_models.into_iter()
-            .map(|(context, bm)| (context, bm.summarize()))
-            .collect()
+    pub fn reset(&self) {
+        self.state_models.reset()
     }
 }
 
 
-/* Many Multiple Bernoulli */
-#[derive(Default, Clone, Debug)]
-pub struct ManyCategoryBernEstimator<T> {
-    state_models: CHashMap<T, CategoryBernEstimator<T>>
+/* Many Multiple Bernoulli, sharded per thread */
+#[derive(Debug)]
+pub struct ManyCategoryBernEstimator<T, S = T>
+where
+    T: Eq + Hash + Ord + Clone + Send,
+    S: Eq + Hash + Ord + Clone + Send,
+{
+    state_models: Sharded<Keyed<T, Keyed<S, BernEstimator>>>
 }
 
-impl<T> ManyCategoryBernEstimator<T> 
-where T: PartialEq + Eq + Hash + Ord + Default + std::fmt::Debug
+impl<T, S> Default for ManyCategoryBernEstimator<T, S>
+where
+    T: Eq + Hash + Ord + Clone + Send,
+    S: Eq + Hash + Ord + Clone + Send,
 {
-    pub fn observe_absent(&self, state: T, substate: T) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe_absent(substate)
-        } else {
-            let cbm = CategoryBernEstimator::default();
-            cbm.observe_absent(substate);
-            self.state_models.insert_new(state, cbm)
+    fn default() -> Self {
+        ManyCategoryBernEstimator {
+            state_models: Sharded::default(),
         }
     }
+}
 
-    pub fn observe_present(&self, state: T, substate: T) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe_present(substate)
-        } else {
-            let cbm = CategoryBernEstimator::default();
-            cbm.observe_present(substate);
-            self.state_models.insert_new(state, cbm)
-        }
+impl<T, S> ManyCategoryBernEstimator<T, S>
+where
+    T: Eq + Hash + Ord + Clone + Send,
+    S: Eq + Hash + Ord + Clone + Send,
+{
+    pub fn observe_absent(&self, state: T, substate: S) {
+        self.observe_absent_weighted(state, substate, 1.0)
+    }
+
+    pub fn observe_absent_weighted(&self, state: T, substate: S, weight: f64) {
+        self.state_models.observe((state, (substate, false)), weight)
+    }
+
+    pub fn observe_present(&self, state: T, substate: S) {
+        self.observe_present_weighted(state, substate, 1.0)
+    }
+
+    pub fn observe_present_weighted(&self, state: T, substate: S, weight: f64) {
+        self.state_models.observe((state, (substate, true)), weight)
+    }
+
+    /// Summary of everything observed so far, leaving the counts in place
+    pub fn summarize(&self) -> ManyCategoryBernSummary<T, S> {
+        self.state_models.summarize()
     }
 
-    pub fn summarize(self) -> ManyCategoryBernSummary<T> {
-        self.state_models.into_iter()
-            .map(|(context, cbm)| (context, cbm.summarize()))
-            .collect()
+    pub fn reset(&self) {
+        self.state_models.reset()
     }
 }
@@ -0,0 +1,434 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use coruscant_subscriber::anomaly::AnomalyDetector;
+use coruscant_subscriber::anomaly::SummaryTail;
+use coruscant_subscriber::critical;
+use coruscant_subscriber::diff;
+use coruscant_subscriber::export::GraphExporter;
+use coruscant_subscriber::export::NodeColoring;
+use coruscant_subscriber::order;
+use coruscant_subscriber::rootcause;
+use coruscant_subscriber::stats;
+use coruscant_subscriber::processor::DependencySummary;
+use coruscant_subscriber::tree;
+
+
+const USAGE: &str = "\
+Usage: coruscant <command> <summary.jsons> [options]
+       coruscant diff <before.jsons> <after.jsons> [options]
+
+Commands:
+    dot         render the dependency graph as Graphviz DOT
+    mermaid     render the dependency graph as a Mermaid flowchart
+    tree        print the hierarchical call tree from __ROOT_SPAN__ down
+    critical    print each span's wall time split over its critical-path children
+    callsites   list span names declared at more than one call site
+    causal      list follows_from edges and how failures propagate along them
+    rootcause   rank children and combinations of them by contribution to each span's failures
+    origins     list failing descendants each span's failures came from, if traced
+    fanout      list groups of children running alongside each other, and their failures
+    order       compare first-order and higher-order chains by predictive likelihood
+    diff        compare the latest summaries of two files, e.g. before and after a deploy
+    watch       follow the summary file and report shifted fail rates and transitions
+
+Options:
+    --span NAME         only render the chain under this parent span (repeatable),
+                        or start the call tree from this span
+    --influence MU      colour nodes by influence posterior instead of failure rate
+    --history           read the higher-order chains instead of the first-order ones
+                        (not with order, diff or watch)
+    --json              print the root-cause report, the diff or the alerts as JSON
+    --dot               render the diff as an annotated Graphviz DOT graph
+    --interval SECS     seconds between two reads of the summary file (watch, default 10)
+    --baseline N        compare against the last N windows instead of everything before (watch)
+    --significance P    p-value under which a shift is reported (diff and watch, default 0.001)";
+
+
+type GenericError = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+#[derive(Default, Debug)]
+struct Args {
+    command: String,
+    path: String,
+    other_path: Option<String>,
+    spans: Vec<String>,
+    influence: Option<f64>,
+    history: bool,
+    json: bool,
+    dot: bool,
+    interval: Option<u64>,
+    baseline: Option<usize>,
+    significance: Option<f64>,
+}
+
+impl Args {
+    fn parse() -> Result<Args> {
+        let mut raw: VecDeque<String> = std::env::args().skip(1).collect();
+        let mut args = Args {
+            command: raw.pop_front().ok_or("missing command")?,
+            path: raw.pop_front().ok_or("missing summary path")?,
+            ..Args::default()
+        };
+        if args.command == "diff" {
+            args.other_path = Some(raw.pop_front().ok_or("missing second summary path")?);
+        }
+        while let Some(flag) = raw.pop_front() {
+            let mut value = || raw.pop_front().ok_or(format!("missing value for {}", flag));
+            match flag.as_str() {
+                "--span" => args.spans.push(value()?),
+                "--influence" => args.influence = Some(value()?.parse()?),
+                "--history" => args.history = true,
+                "--json" => args.json = true,
+                "--dot" => args.dot = true,
+                "--interval" => args.interval = Some(value()?.parse()?),
+                "--baseline" => args.baseline = Some(value()?.parse()?),
+                "--significance" => args.significance = Some(value()?.parse()?),
+                _ => return Err(format!("unknown option {}", flag).into()),
+            }
+        }
+        Ok(args)
+    }
+
+    fn exporter<'a>(&self, summary: &'a DependencySummary) -> GraphExporter<'a> {
+        let coloring = match self.influence {
+            Some(mu) => NodeColoring::Influence(mu),
+            None => NodeColoring::FailRate,
+        };
+        GraphExporter::new(summary)
+            .coloring(coloring)
+            .spans(self.spans.clone())
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    if args.history && matches!(args.command.as_str(), "order" | "watch" | "diff") {
+        // order compares both chains, the others read counts the history chains lack
+        return Err(format!("--history does not apply to {}", args.command).into());
+    }
+    match args.command.as_str() {
+        "watch" => return watch(&args),
+        "diff" => return print_diff(&args),
+        _ => {},
+    }
+    let mut summary = DependencySummary::read_latest(&args.path)?;
+    if args.history && !summary.unroll_history() {
+        return Err("summary has no higher-order chains".into());
+    }
+    match args.command.as_str() {
+        "dot" => print!("{}", args.exporter(&summary).to_dot()),
+        "mermaid" => print!("{}", args.exporter(&summary).to_mermaid()),
+        "tree" => match args.spans.first() {
+            Some(span) => print!("{}", tree::build_call_tree_from(&summary, span).render()),
+            None => print!("{}", tree::build_call_tree(&summary).render()),
+        },
+        "critical" => print!("{}", render_critical_path(&summary, &args.spans)),
+        "callsites" => print!("{}", render_callsites(&summary)),
+        "causal" => print!("{}", render_causal(&summary, &args.spans)),
+        "rootcause" => match args.json {
+            true => println!("{}", serde_json::to_string_pretty(&root_causes(&summary, &args.spans))?),
+            false => print!("{}", render_root_causes(&summary, &args.spans)),
+        },
+        "origins" => print!("{}", render_origins(&summary, &args.spans)),
+        "fanout" => print!("{}", render_fan_outs(&summary, &args.spans)),
+        "order" => print!("{}", render_orders(&summary)),
+        command => return Err(format!("unknown command {}", command).into()),
+    }
+    Ok(())
+}
+
+fn print_diff(args: &Args) -> Result<()> {
+    let before = DependencySummary::read_latest(&args.path)?;
+    let after = DependencySummary::read_latest(args.other_path.as_ref().ok_or("missing second summary path")?)?;
+    let significance = args.significance.unwrap_or(stats::DEFAULT_SIGNIFICANCE);
+    let summary_diff = diff::diff_summaries(&before, &after, significance).with_spans(args.spans.clone());
+    if args.dot {
+        print!("{}", summary_diff.to_dot());
+    } else if args.json {
+        println!("{}", serde_json::to_string_pretty(&summary_diff.changes_only())?);
+    } else {
+        print!("{}", render_diff(&summary_diff.changes_only()));
+    }
+    Ok(())
+}
+
+fn render_diff(summary_diff: &diff::SummaryDiff) -> String {
+    let value = |value: Option<f64>| value.map(|value| format!("{:.2}%", 100.0 * value)).unwrap_or("-".to_string());
+    let p_value = |p_value: Option<f64>| p_value.map(|p_value| format!("{:.2e}", p_value)).unwrap_or("-".to_string());
+    let mut out = format!(
+        "{:10} {:40} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+        "change", "span", "runs", "fail rate", "runs", "fail rate", "p", "adjusted",
+    );
+    for span_diff in summary_diff.spans.iter() {
+        out += &format!(
+            "{:10} {:40} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            format!("{:?}", span_diff.change),
+            span_diff.span,
+            span_diff.before.map(|(_, total)| total.to_string()).unwrap_or("-".to_string()),
+            value(span_diff.before_rate()),
+            span_diff.after.map(|(_, total)| total.to_string()).unwrap_or("-".to_string()),
+            value(span_diff.after_rate()),
+            p_value(span_diff.p_value),
+            p_value(span_diff.adjusted_p_value),
+        );
+    }
+    out += &format!(
+        "\n{:10} {:40} {:>10} {:>10} {:>10} {:>10}\n",
+        "change", "edge", "before", "after", "p", "adjusted",
+    );
+    for edge_diff in summary_diff.edges.iter() {
+        out += &format!(
+            "{:10} {:40} {:>10} {:>10} {:>10} {:>10}\n",
+            format!("{:?}", edge_diff.change),
+            format!("{} [ {} --> {} ]", edge_diff.span, edge_diff.state, edge_diff.next_state),
+            value(edge_diff.before),
+            value(edge_diff.after),
+            p_value(edge_diff.p_value),
+            p_value(edge_diff.adjusted_p_value),
+        );
+    }
+    out
+}
+
+/* Report alerts on every window appended to the summary file, starting with the latest one */
+fn watch(args: &Args) -> Result<()> {
+    let mut detector = AnomalyDetector::default();
+    if let Some(significance) = args.significance {
+        detector = detector.with_significance(significance);
+    }
+    let interval = Duration::from_secs(args.interval.unwrap_or(10));
+    // summaries are cumulative, only those the baseline reaches back to are kept
+    let keep = args.baseline.map_or(2, |windows| windows + 3);
+    let mut tail = SummaryTail::new(&args.path);
+    let mut history: VecDeque<DependencySummary> = VecDeque::new();
+    let mut first_read = true;
+    let mut read: usize = 0;
+    loop {
+        let (appended, truncated) = match tail.read_appended() {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("Failed to read {}: {}", args.path, e);
+                std::thread::sleep(interval);
+                continue;
+            },
+        };
+        if truncated {
+            history.clear();
+            read = 0;
+        }
+        // the first read only reports the latest window
+        let skipped = match first_read {
+            true => appended.len().saturating_sub(1),
+            false => 0,
+        };
+        first_read = false;
+        for (idx, summary) in appended.into_iter().enumerate() {
+            history.push_back(summary);
+            read += 1;
+            if history.len() > keep {
+                history.pop_front();
+            }
+            if idx < skipped {
+                continue;
+            }
+            for alert in detector.detect_latest(history.make_contiguous(), args.baseline) {
+                match args.json {
+                    true => println!("{}", serde_json::to_string(&alert)?),
+                    false => println!("window {}: {}", read - 1, alert.describe()),
+                }
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn render_critical_path(summary: &DependencySummary, spans: &[String]) -> String {
+    let mut out = String::new();
+    for attribution in critical::analyze_critical_path(summary) {
+        if !spans.is_empty() && !spans.contains(&attribution.span) {
+            continue;
+        }
+        out += &format!(
+            "{}  ({} executions, mean {:.3} ms, busy {:.3} ms, idle {:.3} ms)\n",
+            attribution.span,
+            attribution.executions,
+            1e3 * attribution.mean_wall_time,
+            1e3 * attribution.mean_busy_time,
+            1e3 * attribution.mean_idle_time,
+        );
+        for share in attribution.children.iter() {
+            out += &format!(
+                "    {:40} {:10.3} ms  {:6.2}%\n",
+                share.child,
+                1e3 * share.mean_time,
+                100.0 * share.share,
+            );
+        }
+    }
+    out
+}
+
+fn render_causal(summary: &DependencySummary, spans: &[String]) -> String {
+    let rate = |(count, total): (usize, usize)| match total {
+        0 => "-".to_string(),
+        _ => format!("{:.2}%", 100.0 * count as f64 / total as f64),
+    };
+    let mut out = String::new();
+    for (cause, effects) in summary.make_causal() {
+        for (effect, causal) in effects {
+            if !spans.is_empty() && !spans.contains(&cause) && !spans.contains(&effect) {
+                continue;
+            }
+            out += &format!(
+                "{} ~~> {}  ({} edges)  effect failed {}  after failed cause {}  after succeeded cause {}  cause open {}\n",
+                cause,
+                effect,
+                causal.total.1,
+                rate(causal.total),
+                rate(causal.after_cause_failed),
+                rate(causal.after_cause_succeeded),
+                rate(causal.cause_open),
+            );
+        }
+    }
+    out
+}
+
+fn root_causes(summary: &DependencySummary, spans: &[String]) -> Vec<rootcause::RootCauseReport> {
+    rootcause::analyze_root_causes(summary).into_iter()
+        .filter(|report| spans.is_empty() || spans.contains(&report.span))
+        .collect()
+}
+
+fn render_root_causes(summary: &DependencySummary, spans: &[String]) -> String {
+    let mut out = String::new();
+    for report in root_causes(summary, spans) {
+        out += &format!(
+            "{}  ({} failures in {} executions, baseline {:.2}%, unexplained {:.2}%)\n",
+            report.span,
+            report.failures,
+            report.executions,
+            100.0 * report.baseline,
+            100.0 * report.unexplained,
+        );
+        out += &format!(
+            "    {:11} {:40} {:>10} {:>10} {:>8} {:>12}\n",
+            "kind", "spans", "executions", "fail prob", "lift", "contribution",
+        );
+        for cause in report.causes {
+            out += &format!(
+                "    {:11} {:40} {:10} {:9.2}% {:8.2} {:11.2}%\n",
+                format!("{:?}", cause.kind),
+                cause.spans.join(" + "),
+                cause.executions,
+                100.0 * cause.fail_prob,
+                cause.lift,
+                100.0 * cause.contribution,
+            );
+        }
+    }
+    out
+}
+
+fn render_origins(summary: &DependencySummary, spans: &[String]) -> String {
+    let mut out = String::new();
+    for (span, origins) in summary.make_failure_origins() {
+        if !spans.is_empty() && !spans.contains(&span) {
+            continue;
+        }
+        out += &format!("{}\n", span);
+        let mut origins: Vec<_> = origins.into_iter().collect();
+        origins.sort_by(|(_, (lhs, _)), (_, (rhs, _))| rhs.cmp(lhs));
+        for (origin, (failed, total)) in origins {
+            out += &format!(
+                "    {:40} x{:<8} span failed {:6.2}%\n",
+                origin,
+                total,
+                100.0 * failed as f64 / total as f64,
+            );
+        }
+    }
+    out
+}
+
+fn render_fan_outs(summary: &DependencySummary, spans: &[String]) -> String {
+    let rate = |(count, total): (usize, usize)| match total {
+        0 => "-".to_string(),
+        _ => format!("{:.2}%", 100.0 * count as f64 / total as f64),
+    };
+    let mut out = String::new();
+    for (span, groups) in summary.make_fan_out() {
+        if !spans.is_empty() && !spans.contains(&span) {
+            continue;
+        }
+        out += &format!("{}\n", span);
+        for (group, fan_out) in groups {
+            out += &format!(
+                "    {}  ({} runs, width {:.2})  any failed {}  all failed {}  propagated {}\n",
+                group,
+                fan_out.width.2,
+                fan_out.width.0,
+                rate(fan_out.any_failed),
+                rate(fan_out.all_failed),
+                rate(fan_out.propagated),
+            );
+            for (member, (mean, _, _)) in fan_out.members {
+                out += &format!("        {:40} x{:.2}\n", member, mean);
+            }
+        }
+    }
+    out
+}
+
+fn render_orders(summary: &DependencySummary) -> String {
+    let mut out = format!(
+        "{:40} {:>10} {:>5} {:>12} {:>12} {:>12}\n",
+        "span", "executions", "order", "loglik", "predictive", "gain",
+    );
+    for comparison in order::compare_orders(summary) {
+        for fit in [&comparison.first_order, &comparison.history] {
+            out += &format!(
+                "{:40} {:10} {:5} {:12.4} {:12.4}",
+                comparison.span,
+                comparison.executions,
+                fit.order,
+                fit.log_likelihood,
+                fit.predictive,
+            );
+            if fit.order > 1 {
+                out += &format!(" {:12.4}", comparison.gain());
+            }
+            out += "\n";
+        }
+    }
+    out
+}
+
+fn render_callsites(summary: &DependencySummary) -> String {
+    let mut out = String::new();
+    for (name, callsites) in summary.make_ambiguous_names() {
+        out += &format!("{}\n", name);
+        for callsite in callsites {
+            out += &format!(
+                "    {:40} {}:{}\n",
+                callsite.module_path.unwrap_or(callsite.target),
+                callsite.file.unwrap_or_default(),
+                callsite.line.map(|line| line.to_string()).unwrap_or_default(),
+            );
+        }
+    }
+    out
+}
+
+fn main() {
+    env_logger::Builder::from_default_env()
+        .format_timestamp_micros()
+        .init();
+
+    let result = Args::parse().and_then(run);
+    if let Err(e) = result {
+        eprintln!("{}\n\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+}
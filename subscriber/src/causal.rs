@@ -0,0 +1,225 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use thread_local::ThreadLocal;
+use tracing::Id;
+
+use crate::bernoulli::BernEstimator;
+use crate::bernoulli::BernSummary;
+use crate::estimator::Estimator;
+use crate::estimator::Keyed;
+
+
+/*
+ * Causal edges
+ *
+ *  `follows_from` links a span to a cause that may live in another trace,
+ *  e.g. a queue consumer to the producer of its message. These edges sit
+ *  outside every parent's chain; each one is observed when the effect
+ *  closes, along with whether its cause had failed by then.
+ *
+ *  ContextCausalEstimator: cause_state --> effect_state --> CausalEstimator
+ *
+ *  A cause may close before an effect follows from it, e.g. a producer
+ *  done sending, so the latest closed spans are kept around in ClosedCauses.
+ *  Each thread keeps its own, so closing spans never contend on a lock.
+ */
+
+/// Pseudo-context admitting cause states through the cardinality guard
+pub const CAUSES_CONTEXT: &str = "__CAUSES__";
+
+/// Default number of closed spans kept as possible causes
+pub const DEFAULT_CLOSED_CAUSES: usize = 1024;
+
+pub type ContextCausalEstimator = Keyed<String, Keyed<String, CausalEstimator>>;
+pub type ContextCausalSummary = BTreeMap<String, BTreeMap<String, CausalSummary>>;
+
+/// Whether a cause span failed, set once it fails or closes
+pub type OutcomeHandle = Arc<Mutex<Option<bool>>>;
+
+
+/// A span some other span follows from
+#[derive(Clone, Debug)]
+pub struct Cause {
+    pub state: String,
+    pub outcome: OutcomeHandle,
+}
+
+/// Latest closed spans with their outcomes, kept per thread
+#[derive(Debug)]
+pub struct ClosedCauses {
+    /// Spans kept by each thread, dropping the oldest past it
+    capacity: usize,
+    shards: ThreadLocal<Mutex<RecentCauses>>,
+}
+
+/* A closed span, its outcome handle only built once an effect follows from it */
+#[derive(Clone, Debug)]
+struct ClosedCause {
+    state: String,
+    failed: bool,
+    at: Instant,
+}
+
+/* Closed spans of one thread, in the order they closed */
+#[derive(Debug, Default)]
+struct RecentCauses {
+    causes: HashMap<Id, (u64, ClosedCause)>,
+    /* Closes in order, stale once a reused id closes again */
+    order: VecDeque<(u64, Id)>,
+    closed: u64,
+}
+
+impl ClosedCauses {
+    pub fn new(capacity: usize) -> ClosedCauses {
+        ClosedCauses {
+            capacity,
+            shards: ThreadLocal::new(),
+        }
+    }
+
+    /// Whether closed spans are kept at all, so callers can skip mapping their state
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn remember(&self, id: Id, state: String, failed: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut recent = self.shards.get_or_default().lock().unwrap();
+        recent.closed += 1;
+        let closed = recent.closed;
+        recent.causes.insert(id.clone(), (closed, ClosedCause { state, failed, at: Instant::now() }));
+        recent.order.push_back((closed, id));
+        while recent.order.len() > self.capacity {
+            if let Some((closed, id)) = recent.order.pop_front() {
+                if recent.causes.get(&id).is_some_and(|(latest, _)| *latest == closed) {
+                    recent.causes.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// The latest span closed under this id on any thread
+    pub fn get(&self, id: &Id) -> Option<Cause> {
+        self.shards.iter()
+            .filter_map(|shard| shard.lock().unwrap().causes.get(id).map(|(_, cause)| cause.clone()))
+            .max_by_key(|cause| cause.at)
+            .map(|cause| Cause {
+                state: cause.state,
+                outcome: Arc::new(Mutex::new(Some(cause.failed))),
+            })
+    }
+}
+
+/// A closing effect span against the outcome of one of its causes
+#[derive(Clone, Copy, Debug)]
+pub struct CausalObservation {
+    /// None if the cause was still open and had not failed yet
+    pub cause_failed: Option<bool>,
+    pub effect_failed: bool,
+}
+
+
+/// Failures of effect spans following from one cause span
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CausalSummary {
+    /// Failed effects, out of every edge
+    pub total: BernSummary,
+    /// Failed effects, out of edges whose cause failed
+    pub after_cause_failed: BernSummary,
+    /// Failed effects, out of edges whose cause succeeded
+    pub after_cause_succeeded: BernSummary,
+    /// Edges whose cause was still open, without failing, when the effect closed
+    pub cause_open: BernSummary,
+}
+
+impl CausalSummary {
+    /// Rise in the effect's failure rate when its cause failed, if both
+    /// outcomes of the cause were seen
+    pub fn propagation(&self) -> Option<f64> {
+        let rate = |(fails, total): BernSummary| (total > 0).then(|| fails as f64 / total as f64);
+        Some(rate(self.after_cause_failed)? - rate(self.after_cause_succeeded)?)
+    }
+}
+
+
+/* Causal edge, observing effect failures against cause failures */
+#[derive(Default, Clone, Debug)]
+pub struct CausalEstimator {
+    total: BernEstimator,
+    after_cause_failed: BernEstimator,
+    after_cause_succeeded: BernEstimator,
+    cause_open: BernEstimator,
+}
+
+impl Estimator for CausalEstimator {
+    type Observation = CausalObservation;
+    type Summary = CausalSummary;
+
+    fn observe(&mut self, observation: CausalObservation, weight: f64) {
+        self.total.observe(observation.effect_failed, weight);
+        self.cause_open.observe(observation.cause_failed.is_none(), weight);
+        match observation.cause_failed {
+            Some(true) => self.after_cause_failed.observe(observation.effect_failed, weight),
+            Some(false) => self.after_cause_succeeded.observe(observation.effect_failed, weight),
+            None => {},
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.total.merge(other.total);
+        self.after_cause_failed.merge(other.after_cause_failed);
+        self.after_cause_succeeded.merge(other.after_cause_succeeded);
+        self.cause_open.merge(other.cause_open);
+    }
+
+    fn summarize(&self) -> CausalSummary {
+        CausalSummary {
+            total: self.total.summarize(),
+            after_cause_failed: self.after_cause_failed.summarize(),
+            after_cause_succeeded: self.after_cause_succeeded.summarize(),
+            cause_open: self.cause_open.summarize(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_causes_drop_the_oldest() {
+        let closed = ClosedCauses::new(2);
+        closed.remember(Id::from_u64(1), "producer_a".to_string(), false);
+        closed.remember(Id::from_u64(2), "producer_b".to_string(), true);
+        // a reused id closing again only keeps its latest span
+        closed.remember(Id::from_u64(1), "producer_c".to_string(), false);
+        assert_eq!(closed.get(&Id::from_u64(1)).unwrap().state, "producer_c");
+        assert_eq!(closed.get(&Id::from_u64(2)).unwrap().state, "producer_b");
+        closed.remember(Id::from_u64(3), "producer_d".to_string(), false);
+        assert!(closed.get(&Id::from_u64(2)).is_none());
+        assert_eq!(closed.get(&Id::from_u64(1)).unwrap().state, "producer_c");
+        assert_eq!(*closed.get(&Id::from_u64(3)).unwrap().outcome.lock().unwrap(), Some(false));
+    }
+
+    #[test]
+    fn closed_causes_are_found_across_threads() {
+        let closed = ClosedCauses::new(1);
+        closed.remember(Id::from_u64(1), "producer_a".to_string(), false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| closed.remember(Id::from_u64(1), "producer_b".to_string(), true));
+        });
+        // each thread keeps its own latest spans, and a lookup takes the latest of them
+        let cause = closed.get(&Id::from_u64(1)).unwrap();
+        assert_eq!(cause.state, "producer_b");
+        assert_eq!(*cause.outcome.lock().unwrap(), Some(true));
+        assert!(ClosedCauses::new(0).get(&Id::from_u64(1)).is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use chashmap::CHashMap;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+
+/*
+ * chashmap marks removed buckets instead of emptying them, and only reclaims
+ * them when the table is rebuilt. Resizing follows the number of live keys,
+ * so a map whose keys keep changing, like span ids, fills up with removed
+ * buckets while staying small. Looking up an absent key then scans for an
+ * empty bucket, finds none, and panics.
+ *
+ * CompactingMap rebuilds its table once removals reach 1/16 of the buckets,
+ * which keeps empty buckets around under chashmap's 85% load factor. The
+ * rebuild write-locks the whole table, but only every buckets/16 removals,
+ * so it costs a constant amortized 16 bucket moves per removal.
+ */
+
+/// A `CHashMap` that stays usable under endless inserts and removals
+pub(crate) struct CompactingMap<K, V> {
+    map: CHashMap<K, V>,
+    removals: AtomicUsize,
+}
+
+impl<K: PartialEq + Hash, V> Default for CompactingMap<K, V> {
+    fn default() -> Self {
+        CompactingMap {
+            map: CHashMap::new(),
+            removals: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K: PartialEq + Hash, V> Deref for CompactingMap<K, V> {
+    type Target = CHashMap<K, V>;
+
+    fn deref(&self) -> &CHashMap<K, V> {
+        &self.map
+    }
+}
+
+impl<K: PartialEq + Hash, V> CompactingMap<K, V> {
+    pub(crate) fn remove(&self, key: &K) -> Option<V> {
+        let removed = self.map.remove(key);
+        if removed.is_some() {
+            self.count_removal();
+        }
+        removed
+    }
+
+    /// Replace the value of `key`, removing it if `f` returns none
+    pub(crate) fn alter<F: FnOnce(Option<V>) -> Option<V>>(&self, key: K, f: F) {
+        let mut removed = false;
+        self.map.alter(key, |value| {
+            let had_value = value.is_some();
+            let value = f(value);
+            removed = had_value && value.is_none();
+            value
+        });
+        if removed {
+            self.count_removal();
+        }
+    }
+
+    fn count_removal(&self) {
+        if self.removals.fetch_add(1, Ordering::Relaxed) + 1 >= (self.map.buckets() / 16).max(1) {
+            self.removals.store(0, Ordering::Relaxed);
+            self.map.shrink_to_fit();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absent_keys_stay_readable_after_many_removals() {
+        let map = CompactingMap::default();
+        for key in 0..100_000u64 {
+            map.insert(key, key);
+            assert_eq!(map.remove(&key), Some(key));
+        }
+        assert!(map.get(&u64::MAX).is_none());
+        assert!(map.is_empty());
+    }
+}
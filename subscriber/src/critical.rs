@@ -0,0 +1,84 @@
+use serde::Serialize;
+
+use crate::processor::BUSY_SUBRECORD;
+use crate::processor::DependencySummary;
+use crate::processor::IDLE_SUBRECORD;
+use crate::processor::SELF_SUBRECORD;
+use crate::processor::TOTAL_SUBRECORD;
+
+
+/// Subrecords describing the span as a whole rather than a child
+const SPAN_SUBRECORDS: [&str; 3] = [TOTAL_SUBRECORD, BUSY_SUBRECORD, IDLE_SUBRECORD];
+
+
+/// Share of a parent span's wall time spent on one child along the critical path
+#[derive(Serialize, Clone, Debug)]
+pub struct CriticalPathShare {
+    /// Child span name, or __SELF__ for time not covered by any child
+    pub child: String,
+    /// Seconds on the critical path, averaged over every execution of the parent
+    pub mean_time: f64,
+    /// Fraction of the parent's total wall time
+    pub share: f64,
+}
+
+/// Where a parent span's wall time goes, aggregated across executions
+#[derive(Serialize, Clone, Debug)]
+pub struct CriticalPathAttribution {
+    pub span: String,
+    pub executions: usize,
+    pub mean_wall_time: f64,
+    /// Seconds the span was entered, i.e. actually running
+    pub mean_busy_time: f64,
+    /// Seconds the span was alive but not entered, e.g. parked on an executor
+    pub mean_idle_time: f64,
+    /// Largest share first
+    pub children: Vec<CriticalPathShare>,
+}
+
+
+/// Attribute every span's wall time to its children on the critical path and
+/// its own self/idle time, slowest spans first
+pub fn analyze_critical_path(summary: &DependencySummary) -> Vec<CriticalPathAttribution> {
+    let mut attributions: Vec<CriticalPathAttribution> = summary.critical_path.iter()
+        .filter_map(|(span, times)| {
+            let (mean_wall_time, _, executions) = *times.get(TOTAL_SUBRECORD)?;
+            let total_time = mean_wall_time * executions as f64;
+            let mean_of = |key: &str| times.get(key)
+                .map(|(mean, _, count)| mean * *count as f64 / executions as f64)
+                .unwrap_or(0.0);
+            let mean_busy_time = mean_of(BUSY_SUBRECORD);
+            let mean_idle_time = mean_of(IDLE_SUBRECORD);
+            let mut children: Vec<CriticalPathShare> = times.iter()
+                .filter(|(child, _)| !SPAN_SUBRECORDS.contains(&child.as_str()))
+                .map(|(child, (mean, _, count))| {
+                    let time = mean * *count as f64;
+                    CriticalPathShare {
+                        child: child.clone(),
+                        mean_time: time / executions as f64,
+                        share: if total_time > 0.0 { time / total_time } else { 0.0 },
+                    }
+                })
+                .collect();
+            children.sort_by(|a, b| b.share.total_cmp(&a.share).then(a.child.cmp(&b.child)));
+            Some(CriticalPathAttribution {
+                span: span.clone(),
+                executions,
+                mean_wall_time,
+                mean_busy_time,
+                mean_idle_time,
+                children,
+            })
+        })
+        .collect();
+    attributions.sort_by(|a, b| {
+        b.mean_wall_time.total_cmp(&a.mean_wall_time).then(a.span.cmp(&b.span))
+    });
+    attributions
+}
+
+/// Child dominating the critical path of `span`, other than its self time
+pub fn dominant_child(attribution: &CriticalPathAttribution) -> Option<&CriticalPathShare> {
+    attribution.children.iter()
+        .find(|share| share.child != SELF_SUBRECORD)
+}
@@ -0,0 +1,106 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::estimator::Estimator;
+use crate::estimator::Keyed;
+use crate::normal::NormalEstimator;
+use crate::shard::Sharded;
+
+
+/// Whether a custom observation comes from a closing span or an event in it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObservationSource {
+    Span,
+    Event,
+}
+
+/// A closing span, or an event inside a span, as seen by custom estimators
+#[derive(Clone, Debug)]
+pub struct CustomObservation {
+    pub source: ObservationSource,
+    /// Context of the span's parent
+    pub parent: String,
+    /// State of the span within its parent's chain
+    pub state: String,
+    /// Span fields for a closing span, the event's own fields for an event
+    pub fields: BTreeMap<&'static str, String>,
+}
+
+impl CustomObservation {
+    /// Numeric value of a field, if present and parsable
+    pub fn number(&self, field: &str) -> Option<f64> {
+        self.fields.get(field).and_then(|value| value.parse().ok())
+    }
+}
+
+
+/// An app-specific estimator registered on `DependencyProcessor`
+///
+/// Its summary is written under its registered name in the `custom` section
+/// of `DependencySummary`.
+pub trait CustomEstimator: Send + Sync {
+    fn observe(&self, observation: &CustomObservation, weight: f64);
+
+    fn summarize(&self) -> serde_json::Value;
+
+    fn reset(&self) {}
+}
+
+
+/* Any estimator, fed by a function picking its observation out of spans or events */
+pub struct Extracted<E: Estimator, F> {
+    estimator: Sharded<E>,
+    extract: F,
+}
+
+impl<E, F> Extracted<E, F>
+where
+    E: Estimator,
+    F: Fn(&CustomObservation) -> Option<E::Observation>,
+{
+    pub fn new(extract: F) -> Self {
+        Extracted {
+            estimator: Sharded::default(),
+            extract,
+        }
+    }
+}
+
+impl<E, F> CustomEstimator for Extracted<E, F>
+where
+    E: Estimator,
+    E::Summary: Serialize,
+    F: Fn(&CustomObservation) -> Option<E::Observation> + Send + Sync,
+{
+    fn observe(&self, observation: &CustomObservation, weight: f64) {
+        if let Some(observation) = (self.extract)(observation) {
+            self.estimator.observe(observation, weight);
+        }
+    }
+
+    fn summarize(&self) -> serde_json::Value {
+        serde_json::to_value(self.estimator.summarize()).unwrap_or_default()
+    }
+
+    fn reset(&self) {
+        self.estimator.reset()
+    }
+}
+
+
+pub type FieldNormalEstimator = Keyed<String, Keyed<String, NormalEstimator>>;
+
+/// Mean and stddev of a numeric field per (parent, child), e.g. bytes sent
+/// or retry count, taken from spans or events carrying it
+pub fn field_normal(
+    source: ObservationSource,
+    field: &'static str,
+) -> impl CustomEstimator {
+    Extracted::<FieldNormalEstimator, _>::new(move |observation: &CustomObservation| {
+        if observation.source != source {
+            return None;
+        }
+        observation.number(field)
+            .map(|number| (observation.parent.clone(), (observation.state.clone(), number)))
+    })
+}
@@ -1,131 +1,521 @@
-use chashmap::CHashMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
 use tracing::Event;
 use tracing::Id;
 use tracing::span;
 use tracing::Level;
+use tracing::Metadata;
+use tracing::subscriber::Interest;
 use tracing::subscriber::Subscriber;
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::Layer;
 use tracing_subscriber::registry::LookupSpan;
 
+use crate::causal::Cause;
+use crate::causal::ClosedCauses;
+use crate::causal::DEFAULT_CLOSED_CAUSES;
+use crate::compact::CompactingMap;
+use crate::fanout::FanOutGroup;
+use crate::filter::SpanFilter;
+use crate::processor::ABANDONED_STATE;
 use crate::processor::DependencyProcessor;
+use crate::reaper::OpenSpanStats;
+use crate::reaper::Reaper;
+use crate::record::FieldVisitor;
+use crate::record::PendingChild;
 use crate::record::ROOT_SPAN;
+use crate::record::SpanKey;
 use crate::record::SpanRecord;
+use crate::record::SubspanInterval;
+use crate::sampling::Sampling;
+use crate::sampling::TraceBuffer;
+
+/* Registry marker on spans of traces left out by head sampling */
+struct Unsampled;
+
+/* Nearest retained ancestor of a span that is not modelled */
+#[derive(Clone)]
+struct Collapsed {
+    ancestor: Option<Id>,
+    /// Finalized by the reaper rather than filtered out
+    abandoned: bool,
+}
+
+/* Reaper bookkeeping, only touched by the thread taking a pass */
+#[derive(Default)]
+struct ReaperState {
+    /// Stale spans already warned about
+    reported: HashSet<Id>,
+    /// Abandoned spans by when, their tombstones go after another `max_age`
+    tombstones: VecDeque<(Instant, Id)>,
+}
 
 /// A subscriber layer looking for reliability dependency
 pub struct DependencyLayer {
-  records: CHashMap<Id, SpanRecord>,
+  records: CompactingMap<Id, SpanRecord>,
   root_sr: RwLock<SpanRecord>,
   processor: Arc<DependencyProcessor>,
+  filter: SpanFilter,
+  collapsed: CompactingMap<Id, Collapsed>,
+  sampling: Sampling,
+  reaper: Option<Reaper>,
+  reaper_state: Mutex<ReaperState>,
+  started: Instant,
+  /// Nanoseconds from `started` to the latest reaper pass
+  last_reap: AtomicU64,
+  abandoned: AtomicUsize,
+  orphaned: AtomicUsize,
+  /// Longest activity of a fan-out group, from its first start to its last end
+  fan_out_window: Duration,
+  /// Most children held per parent, waiting on earlier siblings or in a fan-out group
+  max_pending_children: usize,
+  closed_causes: ClosedCauses,
 }
 
+/// Default cap on how long a fan-out group may stay pending
+pub const DEFAULT_FAN_OUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Default cap on how many children a parent holds back
+pub const DEFAULT_MAX_PENDING_CHILDREN: usize = 256;
+
 impl DependencyLayer {
     pub fn construct() -> (DependencyLayer, Arc<DependencyProcessor>) {
-        let processor = Arc::new(DependencyProcessor::new());
+        Self::construct_with(DependencyProcessor::new())
+    }
+
+    /// Construct around a configured processor, e.g. one keying states by span fields
+    pub fn construct_with(processor: DependencyProcessor) -> (DependencyLayer, Arc<DependencyProcessor>) {
+        let processor = Arc::new(processor);
         let layer = DependencyLayer {
-            records: CHashMap::new(),
+            records: CompactingMap::default(),
             root_sr: RwLock::new(SpanRecord::new(Id::from_u64(1), ROOT_SPAN)),
             processor: processor.clone(),
+            filter: SpanFilter::default(),
+            collapsed: CompactingMap::default(),
+            sampling: Sampling::default(),
+            reaper: None,
+            reaper_state: Mutex::new(ReaperState::default()),
+            started: Instant::now(),
+            last_reap: AtomicU64::new(0),
+            abandoned: AtomicUsize::new(0),
+            orphaned: AtomicUsize::new(0),
+            fan_out_window: DEFAULT_FAN_OUT_WINDOW,
+            max_pending_children: DEFAULT_MAX_PENDING_CHILDREN,
+            closed_causes: ClosedCauses::new(DEFAULT_CLOSED_CAUSES),
         };
         (layer, processor)
     }
 
-    fn stacked_span_id(&self, current_sr: &SpanRecord, parent_id: &Id) {
-        match self.records.get_mut(parent_id) {
-            Some(mut parent_sr) => self.stacked_span(current_sr, &mut parent_sr),
-            None => log::warn!("Parent span record not found {:?}", parent_id),
+    /// Only model spans retained by the filter, collapsing the others
+    pub fn with_span_filter(mut self, filter: SpanFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Only record a sample of traces, re-weighting what is kept
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Cap the activity of a fan-out group, later siblings start a new one
+    pub fn with_fan_out_window(mut self, window: Duration) -> Self {
+        self.fan_out_window = window;
+        self
+    }
+
+    /// Cap the children held back per parent, settling a fan-out group once
+    /// it reaches the cap, and handing on children closed ahead of an open
+    /// earlier sibling past it
+    pub fn with_max_pending_children(mut self, max_pending_children: usize) -> Self {
+        self.max_pending_children = max_pending_children.max(1);
+        self
+    }
+
+    /// Keep this many closed spans per thread around for effects following from them later
+    pub fn with_closed_causes(mut self, capacity: usize) -> Self {
+        self.closed_causes = ClosedCauses::new(capacity);
+        self
+    }
+
+    /// Look for spans that stay open for too long, see `crate::reaper`
+    pub fn with_reaper(mut self, reaper: Reaper) -> Self {
+        self.reaper = Some(reaper);
+        self
+    }
+
+    /* Fields are formatted into strings, only capture them if someone reads them */
+    fn reads_fields(&self) -> bool {
+        self.filter.reads_fields() || self.processor.reads_fields()
+    }
+
+    fn maybe_reap(&self) {
+        let reaper = match &self.reaper {
+            Some(reaper) => reaper,
+            None => return,
+        };
+        let now = self.started.elapsed().as_nanos() as u64;
+        let last_reap = self.last_reap.load(Ordering::Relaxed);
+        if now.saturating_sub(last_reap) < reaper.period.as_nanos() as u64 {
+            return;
+        }
+        // whoever moves the clock takes the pass
+        if self.last_reap.compare_exchange(last_reap, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            self.reap(reaper);
         }
     }
 
-    fn stacked_span(&self, current_sr: &SpanRecord, parent_sr: &mut SpanRecord) {
-        if let Some(prev_sr) = &parent_sr.latest {
-            self.record_follows_under(current_sr, prev_sr, parent_sr);
+    fn reap(&self, reaper: &Reaper) {
+        let mut state = match self.reaper_state.try_lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let now = Instant::now();
+        let open_spans = RefCell::new(Vec::new());
+        self.records.retain(|id, sr| {
+            open_spans.borrow_mut().push((sr.creation_time, id.clone()));
+            true
+        });
+        let open_spans = open_spans.into_inner();
+
+        let mut stale = Vec::new();
+        let mut stats = OpenSpanStats {
+            open: open_spans.len(),
+            ..OpenSpanStats::default()
+        };
+        for (creation_time, id) in open_spans {
+            let age = now.saturating_duration_since(creation_time);
+            stats.oldest_age = stats.oldest_age.max(age.as_secs_f64());
+            stats.mean_age += age.as_secs_f64() / stats.open as f64;
+            if age >= reaper.max_age {
+                stale.push((creation_time, id));
+            }
+        }
+        stats.stale = stale.len();
+
+        if reaper.abandon {
+            // abandon children before their parents
+            stale.sort_by(|(lhs, _), (rhs, _)| rhs.cmp(lhs));
+            for (_, id) in stale {
+                self.abandon(&id, &mut state);
+            }
         } else {
-            self.record_stacked_span(current_sr, parent_sr);
+            let reported = stale.into_iter()
+                .map(|(creation_time, id)| {
+                    if !state.reported.contains(&id) {
+                        log::warn!("Span {:?} still open after {:?}", id, now.saturating_duration_since(creation_time));
+                    }
+                    id
+                })
+                .collect();
+            // forget spans that closed since
+            state.reported = reported;
         }
-        parent_sr.latest = Some(Box::new(current_sr.clone()));
+
+        // later hooks are unlikely past another max_age, and ids get reused
+        while state.tombstones.front().is_some_and(|(abandoned_at, _)| now.saturating_duration_since(*abandoned_at) >= reaper.max_age) {
+            let (_, id) = state.tombstones.pop_front().unwrap();
+            self.collapsed.alter(id, |collapsed| collapsed.filter(|collapsed| !collapsed.abandoned));
+        }
+
+        stats.abandoned = self.abandoned.load(Ordering::Relaxed);
+        stats.orphaned = self.orphaned.load(Ordering::Relaxed);
+        self.processor.record_open_spans(stats);
     }
 
-    fn rooted_span(&self, current_sr: &SpanRecord) {
-        if let Some(prev_sr) = &self.root_sr.read().unwrap().latest {
-            self.record_follows_rooted(current_sr, prev_sr);
-        } else {
-            self.record_rooted_span(current_sr);
+    /* Finalize a stale span as if it closed into __ABANDONED__ */
+    fn abandon(&self, id: &Id, state: &mut ReaperState) {
+        if let Some(mut sr) = self.records.remove(id) {
+            log::warn!("Abandoning span {} {:?} after {:?}", sr.name, id, sr.elapsed());
+            self.release_children(&mut sr, false);
+            self.settle_outcome(&sr);
+            self.record_close(&sr, true);
+            if sr.parent.is_none() {
+                self.finish_trace(&sr);
+            }
+            // later hooks on the span or its children see it as collapsed
+            self.collapsed.insert(id.clone(), Collapsed {
+                ancestor: sr.parent.clone(),
+                abandoned: true,
+            });
+            state.tombstones.push_back((Instant::now(), id.clone()));
+            self.abandoned.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn is_unsampled<S>(&self, id: &Id, ctx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        self.sampling.is_head_sampled() && ctx.span(id)
+            .is_some_and(|span| span.extensions().get::<Unsampled>().is_some())
+    }
+
+    fn mark_unsampled<S>(&self, id: &Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Unsampled);
+        }
+    }
+
+    /* Children share their trace's weight and buffer, roots decide both */
+    fn sample_trace(&self, span_record: &mut SpanRecord) -> bool {
+        if let Some(parent_id) = &span_record.parent {
+            if let Some(parent_sr) = self.records.get(parent_id) {
+                span_record.weight = parent_sr.weight;
+                span_record.trace = parent_sr.trace.clone();
+            }
+            return true;
+        }
+        match self.sampling.sample_head() {
+            Some(weight) => {
+                span_record.weight = weight;
+                if self.sampling.is_tail_sampled() {
+                    span_record.trace = Some(Arc::new(Mutex::new(TraceBuffer::new(self.sampling, weight))));
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /* Keep a finished trace if it failed or wins the tail draw */
+    fn finish_trace(&self, root_sr: &SpanRecord) {
+        if let Some(trace) = &root_sr.trace {
+            self.processor.flush_trace(trace);
         }
-        self.root_sr.write().unwrap().latest = Some(Box::new(current_sr.clone()));
     }
 
-    fn record_rooted_span(&self, current_sr: &SpanRecord) {
-        self.processor.record_span(current_sr, &self.root_sr.read().unwrap());
+    /// Nearest retained span at or above `id`, none if that is the root
+    fn retained_id(&self, id: &Id) -> Option<Id> {
+        match self.collapsed.get(id) {
+            Some(collapsed) => collapsed.ancestor.clone(),
+            None => Some(id.clone()),
+        }
     }
 
-    fn record_follows_rooted(&self, current_sr: &SpanRecord, prev_sr: &SpanRecord) {
-        self.processor.record_span_follows(
-            current_sr,
-            prev_sr,
-            &self.root_sr.read().unwrap(),
-        );
+    /* Hand closed children on in creation order, once no earlier sibling is open */
+    fn join_siblings(&self, current_sr: &SpanRecord, parent_sr: &mut SpanRecord, under_root: bool) {
+        let (start, end) = current_sr.active_interval(Instant::now());
+        parent_sr.open_children.remove(&current_sr.sibling_seq);
+        parent_sr.closed_children.insert(current_sr.sibling_seq, PendingChild {
+            key: self.processor.span_key(current_sr),
+            failing: current_sr.failing,
+            trace: current_sr.trace.clone(),
+            weight: current_sr.weight,
+            start,
+            end,
+        });
+        while let Some(&seq) = parent_sr.closed_children.keys().next() {
+            // past the cap, a sibling that stays open loses its place
+            let held = parent_sr.closed_children.len() > self.max_pending_children;
+            if !held && parent_sr.open_children.first().is_some_and(|open| *open < seq) {
+                break;
+            }
+            if let Some((_, child)) = parent_sr.closed_children.pop_first() {
+                self.group_sibling(child, parent_sr, under_root);
+            }
+        }
     }
 
-    fn record_stacked_span(&self, current_sr: &SpanRecord, parent_sr: &SpanRecord) {
-        self.processor.record_span(current_sr, parent_sr);
+    /* Step the parent's chain once a sibling no longer overlaps the pending ones */
+    fn group_sibling(&self, child: PendingChild, parent_sr: &mut SpanRecord, under_root: bool) {
+        let (start, end) = (child.start, child.end);
+        if let Some((since, until)) = parent_sr.pending_window {
+            // a long-lived child would otherwise pull every later one in
+            let overlaps = start < until && end > since;
+            let within_window = end.max(until).saturating_duration_since(since.min(start)) <= self.fan_out_window;
+            let full = parent_sr.pending_children.len() >= self.max_pending_children;
+            if !overlaps || !within_window || full {
+                self.settle_children(parent_sr, under_root);
+            }
+        }
+        parent_sr.pending_window = Some(match parent_sr.pending_window {
+            Some((since, until)) => (since.min(start), until.max(end)),
+            None => (start, end),
+        });
+        parent_sr.pending_children.push(child);
     }
 
-    fn record_follows_under(&self, current_sr: &SpanRecord, prev_sr: &SpanRecord, parent_sr: &SpanRecord) {
-        self.processor.record_span_follows(
-            current_sr,
-            prev_sr,
-            parent_sr,
-        );
+    /* Hand on every closed child left, e.g. once the parent closes */
+    fn release_children(&self, parent_sr: &mut SpanRecord, under_root: bool) {
+        for (_, child) in std::mem::take(&mut parent_sr.closed_children) {
+            self.group_sibling(child, parent_sr, under_root);
+        }
+        self.settle_children(parent_sr, under_root);
     }
 
-    fn record_close(&self, current_sr: &SpanRecord, parent_id: Option<&Id>) {
-        match parent_id {
+    fn settle_children(&self, parent_sr: &mut SpanRecord, under_root: bool) {
+        let mut pending = std::mem::take(&mut parent_sr.pending_children);
+        parent_sr.pending_window = None;
+        let last = match pending.pop() {
+            Some(last) => last,
+            None => return,
+        };
+        let next = if pending.is_empty() {
+            last.key.clone()
+        } else {
+            let group = FanOutGroup::new(pending.into_iter()
+                .chain(std::iter::once(last.clone()))
+                .map(|child| (child.key.state, child.failing)));
+            let key = SpanKey { id: last.key.id.clone(), state: group.state.clone() };
+            // the root never closes, report its groups as they settle
+            if under_root {
+                self.processor.record_fan_out(&group, parent_sr, &last);
+            } else {
+                parent_sr.fan_outs.push(group);
+            }
+            key
+        };
+        self.processor.record_subspan(&next, parent_sr.latest.as_ref(), parent_sr, &last);
+        self.processor.push_history(parent_sr, &next.state);
+        parent_sr.latest = Some(next);
+    }
+
+    // transitions into a span are recorded in creation order, but only once
+    // it closes, so that states may depend on how the span ended
+    fn record_close(&self, current_sr: &SpanRecord, abandoned: bool) {
+        match &current_sr.parent {
             Some(parent_id) => match self.records.get_mut(parent_id) {
-                Some(mut parent_sr) => self.record_close_under(current_sr, &mut parent_sr),
+                Some(mut parent_sr) => {
+                    self.join_siblings(current_sr, &mut parent_sr, false);
+                    self.record_close_under(current_sr, &mut parent_sr, abandoned);
+                    parent_sr.push_subspan(SubspanInterval {
+                        key: self.processor.map_state(current_sr),
+                        start: current_sr.creation_time,
+                        end: Instant::now(),
+                    });
+                },
+                None if self.collapsed.contains_key(parent_id) => {
+                    // the parent's chain is over, still end the child's own
+                    let mut orphanage = SpanRecord::new(parent_id.clone(), ABANDONED_STATE);
+                    self.record_close_under(current_sr, &mut orphanage, abandoned);
+                    self.orphaned.fetch_add(1, Ordering::Relaxed);
+                },
                 None => log::warn!("Parent span record not found {:?}", parent_id),
             },
-            None => self.record_close_under(current_sr, &mut self.root_sr.write().unwrap())
+            None => {
+                let mut root_sr = self.root_sr.write().unwrap();
+                self.join_siblings(current_sr, &mut root_sr, true);
+                // the root never closes, settle once no root span is left running
+                if root_sr.open_children.is_empty() {
+                    self.settle_children(&mut root_sr, true);
+                }
+                self.record_close_under(current_sr, &mut root_sr, abandoned);
+            },
         }
     }
 
-    fn record_close_under(&self, current_sr: &SpanRecord, parent_sr: &mut SpanRecord) {
-    // fn record_close_under(&self, current_sr: &SpanRecord) {
-        if current_sr.failing {
-            if let Some(latest_sr) = &current_sr.latest {
-                self.processor.record_span_fails_from(latest_sr, current_sr);
-                parent_sr.failing_subspans.insert(current_sr.name.to_string());
+    fn record_close_under(&self, current_sr: &SpanRecord, parent_sr: &mut SpanRecord, abandoned: bool) {
+        if !abandoned {
+            self.processor.record_custom_span(current_sr, parent_sr);
+        }
+        self.processor.record_fan_outs(current_sr);
+        self.processor.record_causes(current_sr);
+        if abandoned {
+            self.processor.record_span_abandons(current_sr);
+        } else if current_sr.failing {
+            if let Some(trace) = &current_sr.trace {
+                trace.lock().unwrap().failing = true;
+            }
+            self.processor.propagate_failure_origins(current_sr, parent_sr);
+            if let Some(latest) = &current_sr.latest {
+                self.processor.record_span_fails_from(latest, current_sr);
+                parent_sr.failing_subspans.insert(self.processor.map_state(current_sr));
             } else {
                 self.processor.record_span_fails(current_sr);
-                parent_sr.failing_subspans.insert(current_sr.name.to_string());
+                parent_sr.failing_subspans.insert(self.processor.map_state(current_sr));
             }
-        } else if let Some(latest_sr) = &current_sr.latest {
-            self.processor.record_span_succeeds_from(latest_sr, current_sr);
-            // parent_sr.failing_subspans.insert(current_sr.name.to_string());
+        } else if let Some(latest) = &current_sr.latest {
+            self.processor.record_span_succeeds_from(latest, current_sr);
         } else {
             self.processor.record_span_succeeds(current_sr);
-            // parent_sr.failing_subspans.insert(current_sr.name.to_string());
         }
     }
 
-    // fn record_close_under(&self, current_sr: &SpanRecord) {
-    //     if let Some(parent_sr) = self.records.get(parent_id) {
-    //         if current_sr.failing {
-    //             self.processor.record_span_fails(current_sr, &parent_sr)
-    //         } else {
-    //             self.processor.record_span_succeeds(current_sr, &parent_sr);
-    //         }
-    //     } else {
-    //         log::warn!("Report close under unseen parent {:?}", parent_id);
-    //         self.record_close(current_sr);
-    //     }
-    // }
+    fn record_custom_event(&self, current_id: &Id, event: &Event<'_>) {
+        let current_id = match self.retained_id(current_id) {
+            Some(current_id) => current_id,
+            None => return,
+        };
+        // one record guard at a time, a closing span may hold its parent's
+        let parent_id = match self.records.get(&current_id) {
+            Some(current_sr) => current_sr.parent.clone(),
+            None => return,
+        };
+        let parent = match parent_id {
+            Some(parent_id) => match self.records.get(&parent_id) {
+                Some(parent_sr) => self.processor.map_context(&parent_sr),
+                None => return,
+            },
+            None => self.processor.map_context(&self.root_sr.read().unwrap()),
+        };
+        let mut fields = BTreeMap::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        if let Some(current_sr) = self.records.get(&current_id) {
+            self.processor.record_custom_event(&current_sr, parent, fields);
+        }
+    }
+
+    /* Link a span to a cause it follows from, wherever either one lives */
+    fn record_follows(&self, span: &Id, follows: &Id) {
+        let cause = match self.records.get_mut(follows) {
+            Some(mut follows_sr) => {
+                let failed = follows_sr.failing.then_some(true);
+                Cause {
+                    state: self.processor.map_state(&follows_sr),
+                    outcome: follows_sr.outcome
+                        .get_or_insert_with(|| Arc::new(Mutex::new(failed)))
+                        .clone(),
+                }
+            },
+            None => match self.closed_causes.get(follows) {
+                Some(cause) => cause,
+                None => {
+                    log::warn!("Following from unseen span {:?}", follows);
+                    return;
+                },
+            },
+        };
+        match self.records.get_mut(span) {
+            Some(mut span_sr) => span_sr.causes.push(cause),
+            None => log::warn!("Unseen span {:?} following from {:?}", span, follows),
+        }
+    }
+
+    /* Let spans following from this one know how it ended, even later on */
+    fn settle_outcome(&self, sr: &SpanRecord) {
+        if let Some(outcome) = &sr.outcome {
+            *outcome.lock().unwrap() = Some(sr.failing);
+        }
+        if self.closed_causes.is_enabled() {
+            self.closed_causes.remember(sr.id.clone(), self.processor.map_state(sr), sr.failing);
+        }
+    }
 
     fn record_found_failure(&self, current_id: &Id) {
-        if let Some(mut current_sr) = self.records.get_mut(current_id) {
+        let current_id = match self.retained_id(current_id) {
+            Some(current_id) => current_id,
+            None => return,
+        };
+        if let Some(mut current_sr) = self.records.get_mut(&current_id) {
             current_sr.failing = true;
+            // failures stick, so effects may learn about them before it closes
+            if let Some(outcome) = &current_sr.outcome {
+                *outcome.lock().unwrap() = Some(true);
+            }
         } else {
             log::warn!("Report failure on unseen span {:?}", current_id);
         }
@@ -136,61 +526,345 @@ impl<S> Layer<S> for DependencyLayer
 where
     S: Subscriber + std::fmt::Debug + for<'lookup> LookupSpan<'lookup>,
 {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_span() {
+            self.processor.record_callsite(metadata);
+        }
+        Interest::always()
+    }
+
     fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
-        let span_record = SpanRecord::new(id.clone(), attrs.metadata().name());
+        self.maybe_reap();
+
+        // skip every span of a trace left out by head sampling
+        let raw_parent_id = match attrs.parent() {
+            Some(parent_id) => Some(parent_id.clone()),
+            None => ctx.current_span().id().cloned(),
+        };
+        if raw_parent_id.as_ref().is_some_and(|parent_id| self.is_unsampled(parent_id, &ctx)) {
+            self.mark_unsampled(id, &ctx);
+            return;
+        }
+
+        let mut span_record = SpanRecord::new(id.clone(), attrs.metadata().name());
+        span_record.set_metadata(attrs.metadata());
+        if self.reads_fields() {
+            attrs.record(&mut FieldVisitor(&mut span_record.fields));
+        }
 
         // relate to parent span
-        match attrs.parent() {
-            Some(parent_id) => self.stacked_span_id(&span_record, parent_id),
-            None => if let Some(parent_id) = ctx.current_span().id() {
-                self.stacked_span_id(&span_record, parent_id)
-            } else {
-                self.rooted_span(&span_record)
-            },
+        span_record.parent = raw_parent_id.and_then(|parent_id| self.retained_id(&parent_id));
+
+        // skip filtered spans, remembering where their children attach
+        if !self.filter.retains(attrs.metadata(), &span_record.fields) {
+            self.collapsed.insert(id.clone(), Collapsed {
+                ancestor: span_record.parent,
+                abandoned: false,
+            });
+            return;
+        }
+        if !self.sample_trace(&mut span_record) {
+            self.mark_unsampled(id, &ctx);
+            return;
         }
 
         // prepare to track current span
+        match &span_record.parent {
+            Some(parent_id) => if let Some(mut parent_sr) = self.records.get_mut(parent_id) {
+                span_record.sibling_seq = parent_sr.open_child();
+            },
+            None => span_record.sibling_seq = self.root_sr.write().unwrap().open_child(),
+        }
         if let Some(maybe_sr) = self.records.insert(id.clone(), span_record) {
             log::warn!("Latest span of {:?} still remained {:?}", id, maybe_sr);
         }
     }
 
     fn on_close(&self, id: Id, ctx: Context<'_, S>) {
-        if let Some(sr) = self.records.remove(&id) {
-            self.record_close(&sr, ctx.current_span().id());
-            // self.record_close_under(&sr);
+        if self.collapsed.remove(&id).is_some() || self.is_unsampled(&id, &ctx) {
+            return;
+        }
+        if let Some(mut sr) = self.records.remove(&id) {
+            self.release_children(&mut sr, false);
+            self.settle_outcome(&sr);
+            self.processor.record_critical_path(&sr);
+            self.record_close(&sr, false);
+            if sr.parent.is_none() {
+                self.finish_trace(&sr);
+            }
         } else {
             log::warn!("Closing unseen span {:?} with a record", id);
         }
     }
 
-    // TODO: on_enter + on_exit ?
+    fn on_record(&self, id: &Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if !self.reads_fields() || self.collapsed.contains_key(id) || self.is_unsampled(id, &ctx) {
+            return;
+        }
+        if let Some(mut sr) = self.records.get_mut(id) {
+            values.record(&mut FieldVisitor(&mut sr.fields));
+        } else {
+            log::warn!("Recording fields on unseen span {:?}", id);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if self.collapsed.contains_key(id) || self.is_unsampled(id, &ctx) {
+            return;
+        }
+        if let Some(mut sr) = self.records.get_mut(id) {
+            sr.enter();
+        } else {
+            log::warn!("Entering unseen span {:?}", id);
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if self.collapsed.contains_key(id) || self.is_unsampled(id, &ctx) {
+            return;
+        }
+        if let Some(mut sr) = self.records.get_mut(id) {
+            sr.exit();
+        } else {
+            log::warn!("Exiting unseen span {:?}", id);
+        }
+    }
 
     fn on_follows_from(&self, span: &span::Id, follows: &span::Id, ctx: Context<'_, S>) {
-        if let Some(span_sr) = self.records.get(span) {
-            if let Some(follows_sr) = self.records.get(follows) {
-                match ctx.current_span().id() {
-                    Some(parent_id) => match self.records.get(parent_id) {
-                        Some(parent_sr) => self.record_follows_under(&span_sr, &follows_sr, &parent_sr),
-                        None => self.record_follows_rooted(&span_sr, &follows_sr),
-                    },
-                    None => self.record_follows_rooted(&span_sr, &follows_sr),
-                };   
-            }
+        if self.is_unsampled(span, &ctx) || self.is_unsampled(follows, &ctx) {
+            return;
+        }
+        match (self.retained_id(span), self.retained_id(follows)) {
+            (Some(span), Some(follows)) if span != follows => self.record_follows(&span, &follows),
+            _ => {},
         }
     }
 
     // TODO: on_id_change ?
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let span_id = match event.parent() {
+            Some(span_id) => Some(span_id.clone()),
+            None => ctx.current_span().id().cloned(),
+        };
+        let span_id = match span_id {
+            Some(span_id) if !self.is_unsampled(&span_id, &ctx) => span_id,
+            _ => return,
+        };
         if event.metadata().level() == &Level::ERROR {
-            match event.parent() {
-                Some(span_id) => self.record_found_failure(span_id),
-                None => if let Some(span_id) = ctx.current_span().id() {
-                    self.record_found_failure(span_id)
-                },
-            }
+            self.record_found_failure(&span_id);
         }
+        if self.processor.has_custom_estimators() {
+            self.record_custom_event(&span_id, event);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::bernoulli::BernEstimator;
+    use crate::custom::Extracted;
+    use crate::custom::ObservationSource;
+    use crate::estimator::Keyed;
+    use crate::limits::CardinalityLimits;
+    use crate::limits::OTHER_STATE;
+    use crate::processor::DependencySummary;
+    use crate::processor::INITIAL_STATE;
+
+    fn traced<F: FnOnce()>(layer: DependencyLayer, f: F) {
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), f)
+    }
+
+    #[test]
+    fn children_of_abandoned_spans_are_orphaned() {
+        let (layer, processor) = DependencyLayer::construct();
+        let reaper = Reaper::new(Duration::from_millis(100)).with_period(Duration::ZERO).abandoning();
+        traced(layer.with_reaper(reaper), || {
+            let parent = info_span!("parent");
+            std::thread::sleep(Duration::from_millis(60));
+            let child = info_span!(parent: &parent, "child");
+            std::thread::sleep(Duration::from_millis(60));
+            // the parent is stale by now, its child is not
+            drop(info_span!("tick"));
+            drop(child);
+            drop(parent);
+            drop(info_span!("tick"));
+        });
+        let open_spans = processor.summarize().open_spans;
+        assert_eq!(open_spans.abandoned, 1);
+        assert_eq!(open_spans.orphaned, 1);
+        assert_eq!(open_spans.open, 0);
+    }
+
+    #[test]
+    fn custom_events_see_admitted_parent_and_state() {
+        let processor = DependencyProcessor::new()
+            .with_limits(CardinalityLimits::default().with_max_states(1))
+            .with_estimator("hits", Extracted::<Keyed<String, BernEstimator>, _>::new(|obs| {
+                let key = format!("{} / {}", obs.parent, obs.state);
+                (obs.source == ObservationSource::Event).then(|| (key, obs.fields.contains_key("hit")))
+            }));
+        let (layer, processor) = DependencyLayer::construct_with(processor);
+        traced(layer, || {
+            let parent = info_span!("parent");
+            let _parent = parent.enter();
+            info_span!("call_a").in_scope(|| tracing::info!(hit = true, "cache"));
+            info_span!("call_b").in_scope(|| tracing::info!("cache"));
+        });
+        let hits: BTreeMap<String, (usize, usize)> = serde_json::from_value(
+            processor.summarize().make_custom("hits").unwrap(),
+        ).unwrap();
+        assert_eq!(hits.get("parent / call_a"), Some(&(1, 1)));
+        assert_eq!(hits.get(&format!("parent / {}", OTHER_STATE)), Some(&(0, 1)));
     }
 
-}
\ No newline at end of file
+    fn next_states(summary: &DependencySummary, context: &str, state: &str) -> Vec<String> {
+        summary.span_markov.get(context)
+            .and_then(|states| states.get(state))
+            .map(|next| next.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn sequential_siblings_form_no_group() {
+        let (layer, processor) = DependencyLayer::construct();
+        traced(layer, || {
+            info_span!("parent").in_scope(|| {
+                let a = info_span!("call_a");
+                a.in_scope(|| {});
+                let b = info_span!("call_b");
+                b.in_scope(|| {});
+            });
+        });
+        let summary = processor.summarize();
+        assert!(summary.fan_out.is_empty());
+        // b closes first, dropped in reverse, yet a was created first
+        assert_eq!(next_states(&summary, "parent", INITIAL_STATE), vec!["call_a"]);
+        assert_eq!(next_states(&summary, "parent", "call_a"), vec!["call_b"]);
+    }
+
+    #[test]
+    fn interleaved_siblings_form_a_group() {
+        let (layer, processor) = DependencyLayer::construct();
+        traced(layer, || {
+            info_span!("parent").in_scope(|| {
+                let a = info_span!("call_a");
+                let b = info_span!("call_b");
+                a.in_scope(|| {});
+                b.in_scope(|| {});
+                a.in_scope(|| {});
+            });
+        });
+        let summary = processor.summarize();
+        let group = summary.fan_out["parent"].get("[call_a & call_b]").unwrap();
+        assert_eq!(group.width.0, 2.0);
+        assert_eq!(next_states(&summary, "parent", INITIAL_STATE), vec!["[call_a & call_b]"]);
+    }
+
+    #[test]
+    fn long_lived_siblings_leave_the_group_past_the_window() {
+        let run = |window: Duration| {
+            let (layer, processor) = DependencyLayer::construct();
+            traced(layer.with_fan_out_window(window), || {
+                info_span!("parent").in_scope(|| {
+                    let background = info_span!("background");
+                    background.in_scope(|| {});
+                    std::thread::sleep(Duration::from_millis(30));
+                    info_span!("call_a").in_scope(|| {});
+                    info_span!("call_b").in_scope(|| {});
+                    background.in_scope(|| {});
+                });
+            });
+            processor.summarize()
+        };
+        let summary = run(DEFAULT_FAN_OUT_WINDOW);
+        assert_eq!(next_states(&summary, "parent", INITIAL_STATE), vec!["[background & call_a & call_b]"]);
+        let summary = run(Duration::from_millis(20));
+        assert!(summary.fan_out.is_empty());
+        assert_eq!(next_states(&summary, "parent", "background"), vec!["call_a"]);
+        assert_eq!(next_states(&summary, "parent", "call_a"), vec!["call_b"]);
+    }
+
+    #[test]
+    fn root_spans_group_like_siblings() {
+        let (layer, processor) = DependencyLayer::construct();
+        traced(layer, || {
+            info_span!("call_a").in_scope(|| {});
+            info_span!("call_b").in_scope(|| {});
+            let c = info_span!("call_c");
+            let d = info_span!("call_d");
+            c.in_scope(|| {});
+            d.in_scope(|| {});
+            c.in_scope(|| {});
+        });
+        let summary = processor.summarize();
+        assert_eq!(next_states(&summary, ROOT_SPAN, "call_a"), vec!["call_b"]);
+        assert_eq!(next_states(&summary, ROOT_SPAN, "call_b"), vec!["[call_c & call_d]"]);
+        assert!(summary.fan_out[ROOT_SPAN].contains_key("[call_c & call_d]"));
+    }
+
+    #[test]
+    fn effects_follow_from_closed_causes() {
+        let (layer, processor) = DependencyLayer::construct();
+        traced(layer, || {
+            let producer = info_span!("producer");
+            producer.in_scope(|| tracing::error!("send failed"));
+            let producer_id = producer.id();
+            drop(producer);
+            let consumer = info_span!("consumer");
+            consumer.follows_from(producer_id);
+        });
+        let causal = processor.summarize().make_causal();
+        let edge = &causal["producer"]["consumer"];
+        assert_eq!(edge.after_cause_failed, (0, 1));
+        assert_eq!(edge.cause_open, (0, 1));
+    }
+
+    #[test]
+    fn history_chains_keep_creation_order() {
+        let (layer, processor) = DependencyLayer::construct_with(DependencyProcessor::new().with_markov_order(2));
+        traced(layer, || {
+            info_span!("parent").in_scope(|| {
+                let a = info_span!("call_a");
+                a.in_scope(|| {});
+                let b = info_span!("call_b");
+                b.in_scope(|| {});
+                // b closes before a
+                drop(b);
+                drop(a);
+            });
+        });
+        let history_markov = processor.summarize().history_markov.unwrap();
+        let chain = &history_markov.chains["parent"];
+        let after_a = format!("{} > call_a", INITIAL_STATE);
+        assert_eq!(chain[&after_a].keys().collect::<Vec<_>>(), vec!["call_a > call_b"]);
+        assert!(chain.contains_key("call_a > call_b"));
+        assert!(!chain.contains_key("call_b > call_a"));
+    }
+
+    #[test]
+    fn overlapping_roots_settle_at_the_pending_cap() {
+        let (layer, processor) = DependencyLayer::construct();
+        traced(layer.with_max_pending_children(4), || {
+            let roots: Vec<_> = (0 .. 10).map(|_| info_span!("call")).collect();
+            for root in roots.iter().chain(roots.iter()) {
+                root.in_scope(|| {});
+            }
+            // the latest roots close first, ahead of the earlier ones
+            for root in roots.into_iter().rev() {
+                drop(root);
+            }
+        });
+        let summary = processor.summarize();
+        let (mean, _, groups) = summary.fan_out[ROOT_SPAN]["[call]"].width;
+        // ten overlapping roots in groups of at most four
+        assert_eq!(groups, 3);
+        assert!((mean * groups as f64 - 10.0).abs() < 1e-9);
+    }
+}
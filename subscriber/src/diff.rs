@@ -0,0 +1,424 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::analysis;
+use crate::anomaly::Window;
+use crate::bernoulli::BernSummary;
+use crate::export::dot_id;
+use crate::export::escape_dot;
+use crate::markov::MarkovSummary;
+use crate::markov::last_state;
+use crate::processor::DependencySummary;
+use crate::processor::FAILURE_STATE;
+use crate::record::ROOT_SPAN;
+use crate::stats;
+
+
+/*
+ * Differences between two summaries, e.g. before and after a deploy
+ *
+ *  Spans and chain edges present on one side only are added or removed.
+ *  Those present on both sides are changed when Fisher's exact test on their
+ *  recorded samples rejects equal fail rates, or equal transition
+ *  probabilities, at the given significance. Exact tests hold up on the
+ *  small counts of rare spans and edges, where chi-square does not. Edges
+ *  need the state counts of both summaries to be tested, and edges between
+ *  root spans are left out as their order means little.
+ *
+ *  A summary holds hundreds of spans and edges, so p-values are adjusted
+ *  with Benjamini-Hochberg over all of them before being compared to the
+ *  significance, controlling the false discovery rate.
+ */
+
+/// How a span or edge differs between the two summaries
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SpanDiff {
+    pub span: String,
+    pub change: Change,
+    /// Failures and executions recorded before
+    pub before: Option<BernSummary>,
+    /// Failures and executions recorded after
+    pub after: Option<BernSummary>,
+    /// Test of equal fail rates, if the span ran on both sides
+    pub p_value: Option<f64>,
+    /// Benjamini-Hochberg adjusted over every test of the diff
+    pub adjusted_p_value: Option<f64>,
+}
+
+impl SpanDiff {
+    pub fn before_rate(&self) -> Option<f64> {
+        self.before.and_then(rate)
+    }
+
+    pub fn after_rate(&self) -> Option<f64> {
+        self.after.and_then(rate)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EdgeDiff {
+    /// Parent span whose chain holds the edge
+    pub span: String,
+    pub state: String,
+    pub next_state: String,
+    pub change: Change,
+    /// Transition probability before
+    pub before: Option<f64>,
+    /// Transition probability after
+    pub after: Option<f64>,
+    /// Test of equal transition probabilities, if the state was counted on both sides
+    pub p_value: Option<f64>,
+    /// Benjamini-Hochberg adjusted over every test of the diff
+    pub adjusted_p_value: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SummaryDiff {
+    pub significance: f64,
+    pub spans: Vec<SpanDiff>,
+    pub edges: Vec<EdgeDiff>,
+}
+
+
+/// Compare every span and chain edge of two summaries
+pub fn diff_summaries(before: &DependencySummary, after: &DependencySummary, significance: f64) -> SummaryDiff {
+    let span_names: BTreeSet<&String> = before.fail_bernoulli.keys()
+        .chain(after.fail_bernoulli.keys())
+        .collect();
+    let mut spans: Vec<SpanDiff> = span_names.into_iter()
+        .map(|span| {
+            let before = before.fail_bernoulli.get(span).map(|fail_summary| fail_summary.samples);
+            let after = after.fail_bernoulli.get(span).map(|fail_summary| fail_summary.samples);
+            let p_value = match (before, after) {
+                (Some((before_fails, before_total)), Some((after_fails, after_total))) => Some(stats::fisher_exact(
+                    before_fails,
+                    before_total.saturating_sub(before_fails),
+                    after_fails,
+                    after_total.saturating_sub(after_fails),
+                )),
+                _ => None,
+            };
+            SpanDiff {
+                span: span.clone(),
+                change: classify(before.is_some(), after.is_some(), None, significance),
+                before,
+                after,
+                p_value,
+                adjusted_p_value: None,
+            }
+        })
+        .collect();
+
+    let (before_counts, after_counts) = (Window::from_summary(before), Window::from_summary(after));
+    let mut edges = Vec::new();
+    let contexts: BTreeSet<&String> = before.span_markov.keys()
+        .chain(after.span_markov.keys())
+        .filter(|context| *context != ROOT_SPAN)
+        .collect();
+    for context in contexts {
+        let before_chain = before.span_markov.get(context);
+        let after_chain = after.span_markov.get(context);
+        let transitions: BTreeSet<(&String, &String)> = [before_chain, after_chain].into_iter()
+            .flatten()
+            .flat_map(|chain| chain.iter())
+            .flat_map(|(state, next)| next.keys().map(move |next_state| (state, next_state)))
+            .collect();
+        for (state, next_state) in transitions {
+            let prob = |chain: Option<&MarkovSummary<String>>| {
+                chain.and_then(|chain| chain.get(state)).and_then(|next| next.get(next_state)).copied()
+            };
+            let (before_prob, after_prob) = (prob(before_chain), prob(after_chain));
+            let row = |window: &Window| {
+                let next_counts = window.transitions.get(context)?.get(state)?;
+                let count = next_counts.get(next_state).copied().unwrap_or(0.0);
+                Some((count as usize, (next_counts.values().sum::<f64>() - count).max(0.0) as usize))
+            };
+            let p_value = match (row(&before_counts), row(&after_counts)) {
+                (Some((before_count, before_rest)), Some((after_count, after_rest))) => {
+                    Some(stats::fisher_exact(before_count, before_rest, after_count, after_rest))
+                },
+                _ => None,
+            };
+            edges.push(EdgeDiff {
+                span: context.clone(),
+                state: state.clone(),
+                next_state: next_state.clone(),
+                change: classify(before_prob.is_some(), after_prob.is_some(), None, significance),
+                before: before_prob,
+                after: after_prob,
+                p_value,
+                adjusted_p_value: None,
+            });
+        }
+    }
+
+    let p_values: Vec<f64> = spans.iter().filter_map(|span_diff| span_diff.p_value)
+        .chain(edges.iter().filter_map(|edge_diff| edge_diff.p_value))
+        .collect();
+    let mut adjusted = stats::benjamini_hochberg(&p_values).into_iter();
+    for span_diff in spans.iter_mut().filter(|span_diff| span_diff.p_value.is_some()) {
+        span_diff.adjusted_p_value = adjusted.next();
+        span_diff.change = classify(true, true, span_diff.adjusted_p_value, significance);
+    }
+    for edge_diff in edges.iter_mut().filter(|edge_diff| edge_diff.p_value.is_some()) {
+        edge_diff.adjusted_p_value = adjusted.next();
+        edge_diff.change = classify(
+            edge_diff.before.is_some(),
+            edge_diff.after.is_some(),
+            edge_diff.adjusted_p_value,
+            significance,
+        );
+    }
+
+    SummaryDiff {
+        significance,
+        spans,
+        edges,
+    }
+}
+
+fn classify(before: bool, after: bool, p_value: Option<f64>, significance: f64) -> Change {
+    match (before, after) {
+        (false, true) => Change::Added,
+        (true, false) => Change::Removed,
+        _ if p_value.is_some_and(|p_value| p_value < significance) => Change::Changed,
+        _ => Change::Unchanged,
+    }
+}
+
+fn rate((fails, total): BernSummary) -> Option<f64> {
+    (total > 0).then(|| fails as f64 / total as f64)
+}
+
+
+impl SummaryDiff {
+    /// Keep the chains of the given parent spans (all of them if empty),
+    /// along with those spans and their children
+    pub fn with_spans<I: IntoIterator<Item = String>>(mut self, spans: I) -> Self {
+        let spans: BTreeSet<String> = spans.into_iter().collect();
+        if !spans.is_empty() {
+            self.edges.retain(|edge_diff| spans.contains(&edge_diff.span));
+            let children: BTreeSet<&str> = self.edges.iter()
+                .flat_map(|edge_diff| [edge_diff.state.as_str(), edge_diff.next_state.as_str()])
+                .flat_map(analysis::state_spans)
+                .collect();
+            self.spans.retain(|span_diff| {
+                spans.contains(&span_diff.span) || children.contains(span_diff.span.as_str())
+            });
+        }
+        self
+    }
+
+    /// Drop everything unchanged
+    pub fn changes_only(mut self) -> Self {
+        self.spans.retain(|span_diff| span_diff.change != Change::Unchanged);
+        self.edges.retain(|edge_diff| edge_diff.change != Change::Unchanged);
+        self
+    }
+
+    /// Both chains overlaid, one cluster per parent span, labelled before -> after
+    ///
+    /// Added nodes and edges are green and removed ones dashed grey. Nodes whose
+    /// fail rate changed, and edges into failure whose probability changed, are
+    /// red when worse and blue when better; other changed edges are amber.
+    pub fn to_dot(&self) -> String {
+        let span_diffs: BTreeMap<&str, &SpanDiff> = self.spans.iter()
+            .map(|span_diff| (span_diff.span.as_str(), span_diff))
+            .collect();
+        let mut clusters: BTreeMap<&str, Vec<&EdgeDiff>> = BTreeMap::new();
+        for edge_diff in self.edges.iter() {
+            clusters.entry(edge_diff.span.as_str()).or_default().push(edge_diff);
+        }
+
+        let mut out = String::new();
+        writeln!(out, "digraph dependency_diff {{").unwrap();
+        writeln!(out, "    rankdir=LR;").unwrap();
+        writeln!(out, "    node [style=filled, fillcolor=\"#ffffff\"];").unwrap();
+        for (cluster_idx, (parent, edge_diffs)) in clusters.into_iter().enumerate() {
+            writeln!(out, "    subgraph cluster_{} {{", cluster_idx).unwrap();
+            writeln!(out, "        label=\"{}\";", escape_dot(parent)).unwrap();
+            let states: BTreeSet<&str> = edge_diffs.iter()
+                .flat_map(|edge_diff| [edge_diff.state.as_str(), edge_diff.next_state.as_str()])
+                .collect();
+            for state in states {
+                let span_diff = span_diffs.get(last_state(state)).filter(|_| !analysis::is_special_state(state));
+                let label = match span_diff {
+                    Some(span_diff) => format!(
+                        "{}\\n({} -> {})",
+                        escape_dot(state),
+                        format_value(span_diff.before_rate()),
+                        format_value(span_diff.after_rate()),
+                    ),
+                    None => escape_dot(state),
+                };
+                let attributes = match span_diff {
+                    Some(span_diff) => change_attributes(
+                        span_diff.change,
+                        span_diff.after_rate().zip(span_diff.before_rate()).map(|(after, before)| after > before),
+                        true,
+                    ),
+                    None => String::new(),
+                };
+                writeln!(
+                    out,
+                    "        \"{}\" [label=\"{}\"{}];",
+                    escape_dot(&dot_id(parent, state)),
+                    label,
+                    attributes,
+                ).unwrap();
+            }
+            for edge_diff in edge_diffs {
+                let worse = edge_diff.after.zip(edge_diff.before)
+                    .filter(|_| edge_diff.next_state == FAILURE_STATE)
+                    .map(|(after, before)| after > before);
+                writeln!(
+                    out,
+                    "        \"{}\" -> \"{}\" [label=\"{} -> {}\"{}];",
+                    escape_dot(&dot_id(parent, &edge_diff.state)),
+                    escape_dot(&dot_id(parent, &edge_diff.next_state)),
+                    format_value(edge_diff.before),
+                    format_value(edge_diff.after),
+                    change_attributes(edge_diff.change, worse, false),
+                ).unwrap();
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+fn format_value(value: Option<f64>) -> String {
+    match value {
+        Some(value) => format!("{:.3}", value),
+        None => "-".to_string(),
+    }
+}
+
+/// Extra DOT attributes of a node or edge
+fn change_attributes(change: Change, worse: Option<bool>, node: bool) -> String {
+    let (color, dashed) = match node {
+        true => ("fillcolor", "\"filled,dashed\""),
+        false => ("color", "dashed"),
+    };
+    match (change, worse) {
+        (Change::Added, _) => format!(", {}=\"#b8e6b8\", penwidth=2", color),
+        (Change::Removed, _) => format!(", {}=\"#d0d0d0\", style={}", color, dashed),
+        (Change::Changed, Some(true)) => format!(", {}=\"#ff9999\", penwidth=2", color),
+        (Change::Changed, Some(false)) => format!(", {}=\"#99bbff\", penwidth=2", color),
+        (Change::Changed, None) => format!(", {}=\"#ffcc66\", penwidth=2", color),
+        (Change::Unchanged, _) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /* handle moves on from call_a to call_b or call_c, failing in its samples */
+    fn summary(spans: &[(&str, BernSummary)], to_call_b: f64, samples: usize) -> DependencySummary {
+        let fail_bernoulli: BTreeMap<&str, serde_json::Value> = spans.iter()
+            .map(|(span, (fails, total))| (*span, json!({
+                // weighted totals differ, tests only read the samples
+                "total": [100 * fails, 100 * total],
+                "failing_subspans": [],
+                "samples": [fails, total],
+            })))
+            .collect();
+        serde_json::from_value(json!({
+            "span_markov": {"handle": {"call_a": {"call_b": to_call_b, "call_c": 1.0 - to_call_b}}},
+            "state_samples": {"handle": {"call_a": samples}},
+            "fail_bernoulli": fail_bernoulli,
+        })).unwrap()
+    }
+
+    fn change_of<'a>(summary_diff: &'a SummaryDiff, span: &str) -> &'a SpanDiff {
+        summary_diff.spans.iter().find(|span_diff| span_diff.span == span).unwrap()
+    }
+
+    #[test]
+    fn spans_change_on_an_exact_test_of_their_samples() {
+        let before = summary(&[("handle", (2, 100)), ("call_a", (5, 100)), ("call_b", (0, 10))], 0.5, 100);
+        let after = summary(&[("handle", (20, 100)), ("call_a", (6, 100)), ("call_c", (0, 10))], 0.5, 100);
+        let summary_diff = diff_summaries(&before, &after, 1e-3);
+        // fisher_exact(2, 98, 20, 80) is about 5.09e-5
+        let handle = change_of(&summary_diff, "handle");
+        assert_eq!(handle.change, Change::Changed);
+        assert!((handle.p_value.unwrap() - 5.0912e-5).abs() < 1e-8);
+        assert_eq!(handle.before, Some((2, 100)));
+        assert_eq!(change_of(&summary_diff, "call_a").change, Change::Unchanged);
+        assert_eq!(change_of(&summary_diff, "call_b").change, Change::Removed);
+        assert_eq!(change_of(&summary_diff, "call_c").change, Change::Added);
+    }
+
+    #[test]
+    fn few_samples_do_not_make_a_change() {
+        // 0 of 3 against 3 of 3 would pass a chi-square test at 0.05
+        let before = summary(&[("call_a", (0, 3))], 0.0, 3);
+        let after = summary(&[("call_a", (3, 3))], 1.0, 3);
+        let summary_diff = diff_summaries(&before, &after, 0.05);
+        assert_eq!(change_of(&summary_diff, "call_a").change, Change::Unchanged);
+        assert!(summary_diff.edges.iter().all(|edge_diff| edge_diff.change == Change::Unchanged));
+    }
+
+    #[test]
+    fn edges_change_on_their_transition_samples() {
+        let summary_diff = diff_summaries(&summary(&[], 0.3, 100), &summary(&[], 0.5, 100), 0.05);
+        // fisher_exact(30, 70, 50, 50) is about 5.94e-3
+        let edge_diff = summary_diff.edges.iter().find(|edge_diff| edge_diff.next_state == "call_b").unwrap();
+        assert_eq!(edge_diff.change, Change::Changed);
+        assert!((edge_diff.p_value.unwrap() - 5.9373e-3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn many_unchanged_edges_are_not_flagged() {
+        // handle_0 alone would pass at 0.05, fisher_exact(40, 60, 55, 45) is about 0.047
+        let chains = |to_call_b: f64| {
+            let mut span_markov: BTreeMap<String, serde_json::Value> = (0 .. 20)
+                .map(|idx| {
+                    let to_call_b = if idx == 0 { to_call_b } else { 0.5 };
+                    (format!("handle_{}", idx), json!({"call_a": {"call_b": to_call_b, "call_c": 1.0 - to_call_b}}))
+                })
+                .collect();
+            span_markov.insert(ROOT_SPAN.to_string(), json!({"handle_0": {"handle_1": to_call_b, "handle_2": 1.0 - to_call_b}}));
+            let mut state_samples: BTreeMap<String, serde_json::Value> = (0 .. 20)
+                .map(|idx| (format!("handle_{}", idx), json!({"call_a": 100})))
+                .collect();
+            state_samples.insert(ROOT_SPAN.to_string(), json!({"handle_0": 100}));
+            serde_json::from_value::<DependencySummary>(json!({
+                "span_markov": span_markov,
+                "state_samples": state_samples,
+                "fail_bernoulli": {},
+            })).unwrap()
+        };
+        let summary_diff = diff_summaries(&chains(0.4), &chains(0.55), 0.05);
+        assert_eq!(summary_diff.edges.len(), 40);
+        let edge_diff = summary_diff.edges.iter()
+            .find(|edge_diff| edge_diff.span == "handle_0" && edge_diff.next_state == "call_b")
+            .unwrap();
+        assert!(edge_diff.p_value.unwrap() < 0.05);
+        assert!(edge_diff.adjusted_p_value.unwrap() > 0.05);
+        assert!(summary_diff.changes_only().edges.is_empty());
+    }
+
+    #[test]
+    fn with_spans_keeps_chains_and_their_children() {
+        let before = summary(&[("handle", (2, 100)), ("call_b", (0, 10)), ("call_d", (0, 10))], 0.5, 100);
+        let summary_diff = diff_summaries(&before, &before, 1e-3).with_spans(["handle".to_string()]);
+        let spans: Vec<&str> = summary_diff.spans.iter().map(|span_diff| span_diff.span.as_str()).collect();
+        assert_eq!(spans, vec!["call_b", "handle"]);
+        assert_eq!(summary_diff.edges.len(), 2);
+        assert!(summary_diff.changes_only().spans.is_empty());
+    }
+}
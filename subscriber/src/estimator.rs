@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+
+/// A model fit from weighted observations
+///
+/// Estimators are plain single-threaded values; wrap them in
+/// `crate::shard::Sharded` to observe from many threads, and in `Keyed` to
+/// keep one per key, to any depth.
+pub trait Estimator: Default + Send {
+    type Observation;
+    type Summary;
+
+    /// Account for `observation` standing for `weight` observations
+    fn observe(&mut self, observation: Self::Observation, weight: f64);
+
+    /// Absorb another estimator's observations
+    fn merge(&mut self, other: Self);
+
+    fn summarize(&self) -> Self::Summary;
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+
+/// One estimator per key, observing `(key, observation)` pairs
+#[derive(Clone, Debug)]
+pub struct Keyed<K, E> {
+    models: HashMap<K, E>,
+}
+
+impl<K, E> Default for Keyed<K, E> {
+    fn default() -> Self {
+        Keyed {
+            models: HashMap::new(),
+        }
+    }
+}
+
+impl<K, E> Keyed<K, E> {
+    /// Number of keys observed so far
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &E)> {
+        self.models.iter()
+    }
+}
+
+impl<K, E> Estimator for Keyed<K, E>
+where
+    K: Eq + Hash + Ord + Clone + Send,
+    E: Estimator,
+{
+    type Observation = (K, E::Observation);
+    type Summary = BTreeMap<K, E::Summary>;
+
+    fn observe(&mut self, (key, observation): Self::Observation, weight: f64) {
+        self.models.entry(key).or_default().observe(observation, weight)
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (key, model) in other.models {
+            self.models.entry(key).or_default().merge(model);
+        }
+    }
+
+    fn summarize(&self) -> Self::Summary {
+        self.models.iter()
+            .map(|(key, model)| (key.clone(), model.summarize()))
+            .collect()
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::analysis;
+use crate::causal::CausalSummary;
+use crate::markov::MarkovSummary;
+use crate::markov::last_state;
+use crate::processor::DependencySummary;
+use crate::processor::FAILURE_STATE;
+
+
+/* Cluster holding follows_from edges, which cross parent spans and traces */
+const CAUSAL_CLUSTER: &str = "follows_from";
+
+
+/// What decides the fill colour of a node
+#[derive(Default, Clone, Copy, Debug)]
+pub enum NodeColoring {
+    /// Observed failure rate of the span
+    #[default]
+    FailRate,
+    /// Influence posterior on the parent span, with Dirichlet hyperparameter mu
+    Influence(f64),
+}
+
+
+/* Text renderings of the dependency graph, one cluster per parent span */
+pub struct GraphExporter<'a> {
+    summary: &'a DependencySummary,
+    coloring: NodeColoring,
+    spans: BTreeSet<String>,
+}
+
+impl<'a> GraphExporter<'a> {
+    pub fn new(summary: &'a DependencySummary) -> Self {
+        GraphExporter {
+            summary,
+            coloring: NodeColoring::default(),
+            spans: BTreeSet::new(),
+        }
+    }
+
+    pub fn coloring(mut self, coloring: NodeColoring) -> Self {
+        self.coloring = coloring;
+        self
+    }
+
+    /// Only render the chains of these parent spans (all of them if empty)
+    pub fn spans<I: IntoIterator<Item = String>>(mut self, spans: I) -> Self {
+        self.spans = spans.into_iter().collect();
+        self
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph dependency {{").unwrap();
+        writeln!(out, "    rankdir=LR;").unwrap();
+        writeln!(out, "    node [style=filled, fillcolor=\"#ffffff\"];").unwrap();
+        for (cluster_idx, (parent, chain, scores)) in self.clusters().into_iter().enumerate() {
+            writeln!(out, "    subgraph cluster_{} {{", cluster_idx).unwrap();
+            writeln!(out, "        label=\"{}\";", escape_dot(parent)).unwrap();
+            for state in analysis::all_states(chain) {
+                let score = scores.get(&state).copied();
+                writeln!(
+                    out,
+                    "        \"{}\" [label=\"{}\", fillcolor=\"{}\"];",
+                    escape_dot(&dot_id(parent, &state)),
+                    escape_dot(&node_label(&state, score)).replace('\n', "\\n"),
+                    fill_color(&state, score),
+                ).unwrap();
+            }
+            for (state, edges) in chain.iter() {
+                for (next_state, prob) in edges.iter() {
+                    writeln!(
+                        out,
+                        "        \"{}\" -> \"{}\" [label=\"{:.3}\"];",
+                        escape_dot(&dot_id(parent, state)),
+                        escape_dot(&dot_id(parent, next_state)),
+                        prob,
+                    ).unwrap();
+                }
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+        let causal_edges = self.causal_edges();
+        if !causal_edges.is_empty() {
+            writeln!(out, "    subgraph cluster_{} {{", CAUSAL_CLUSTER).unwrap();
+            writeln!(out, "        label=\"{}\";", CAUSAL_CLUSTER).unwrap();
+            writeln!(out, "        style=dashed;").unwrap();
+            for state in causal_states(&causal_edges) {
+                let score = analysis::fail_rate(&self.summary.fail_bernoulli, state);
+                writeln!(
+                    out,
+                    "        \"{}\" [label=\"{}\", fillcolor=\"{}\"];",
+                    escape_dot(&dot_id(CAUSAL_CLUSTER, state)),
+                    escape_dot(&node_label(state, score)).replace('\n', "\\n"),
+                    fill_color(state, score),
+                ).unwrap();
+            }
+            for (cause, effect, causal) in causal_edges {
+                writeln!(
+                    out,
+                    "        \"{}\" -> \"{}\" [style=dashed, label=\"{}\"];",
+                    escape_dot(&dot_id(CAUSAL_CLUSTER, cause)),
+                    escape_dot(&dot_id(CAUSAL_CLUSTER, effect)),
+                    causal_label(causal),
+                ).unwrap();
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::new();
+        let mut styles = Vec::new();
+        let mut node_idx = 0;
+        writeln!(out, "flowchart LR").unwrap();
+        for (cluster_idx, (parent, chain, scores)) in self.clusters().into_iter().enumerate() {
+            writeln!(out, "    subgraph c{} [\"{}\"]", cluster_idx, escape_mermaid(parent)).unwrap();
+            let mut ids = BTreeMap::new();
+            for state in analysis::all_states(chain) {
+                let score = scores.get(&state).copied();
+                let id = format!("n{}", node_idx);
+                node_idx += 1;
+                writeln!(
+                    out,
+                    "        {}[\"{}\"]",
+                    id,
+                    escape_mermaid(&node_label(&state, score)).replace('\n', "<br/>"),
+                ).unwrap();
+                styles.push(format!("    style {} fill:{}", id, fill_color(&state, score)));
+                ids.insert(state, id);
+            }
+            for (state, edges) in chain.iter() {
+                for (next_state, prob) in edges.iter() {
+                    writeln!(
+                        out,
+                        "        {} -->|\"{:.3}\"| {}",
+                        ids[state],
+                        prob,
+                        ids[next_state],
+                    ).unwrap();
+                }
+            }
+            writeln!(out, "    end").unwrap();
+        }
+        let causal_edges = self.causal_edges();
+        if !causal_edges.is_empty() {
+            writeln!(out, "    subgraph {} [\"{}\"]", CAUSAL_CLUSTER, CAUSAL_CLUSTER).unwrap();
+            let mut ids = BTreeMap::new();
+            for state in causal_states(&causal_edges) {
+                let score = analysis::fail_rate(&self.summary.fail_bernoulli, state);
+                let id = format!("n{}", node_idx);
+                node_idx += 1;
+                writeln!(
+                    out,
+                    "        {}[\"{}\"]",
+                    id,
+                    escape_mermaid(&node_label(state, score)).replace('\n', "<br/>"),
+                ).unwrap();
+                styles.push(format!("    style {} fill:{}", id, fill_color(state, score)));
+                ids.insert(state, id);
+            }
+            for (cause, effect, causal) in causal_edges {
+                writeln!(
+                    out,
+                    "        {} -.->|\"{}\"| {}",
+                    ids[cause.as_str()],
+                    escape_mermaid(&causal_label(causal)),
+                    ids[effect.as_str()],
+                ).unwrap();
+            }
+            writeln!(out, "    end").unwrap();
+        }
+        for style in styles {
+            writeln!(out, "{}", style).unwrap();
+        }
+        out
+    }
+
+    fn clusters(&self) -> Vec<(&'a String, &'a MarkovSummary<String>, BTreeMap<String, f64>)> {
+        let influence = match self.coloring {
+            NodeColoring::Influence(mu) => Some(self.summary.influence(mu)),
+            NodeColoring::FailRate => None,
+        };
+        self.summary.span_markov.iter()
+            .filter(|(parent, _)| self.spans.is_empty() || self.spans.contains(*parent))
+            .map(|(parent, chain)| {
+                let scores = match &influence {
+                    Some(influence) => influence.get(parent).cloned().unwrap_or_default(),
+                    None => analysis::all_states(chain).into_iter()
+                        .filter_map(|state| {
+                            analysis::fail_rate(&self.summary.fail_bernoulli, last_state(&state))
+                                .map(|rate| (state, rate))
+                        })
+                        .collect(),
+                };
+                (parent, chain, scores)
+            })
+            .collect()
+    }
+
+    /// follows_from edges touching the selected spans
+    fn causal_edges(&self) -> Vec<(&'a String, &'a String, &'a CausalSummary)> {
+        self.summary.causal.iter()
+            .flat_map(|(cause, effects)| {
+                effects.iter().map(move |(effect, causal)| (cause, effect, causal))
+            })
+            .filter(|(cause, effect, _)| {
+                self.spans.is_empty() || self.spans.contains(*cause) || self.spans.contains(*effect)
+            })
+            .collect()
+    }
+}
+
+fn causal_states<'a>(causal_edges: &[(&'a String, &'a String, &CausalSummary)]) -> BTreeSet<&'a str> {
+    causal_edges.iter()
+        .flat_map(|(cause, effect, _)| [cause.as_str(), effect.as_str()])
+        .collect()
+}
+
+/// Number of edges, and how much a failed cause raises the effect's failure rate
+fn causal_label(causal: &CausalSummary) -> String {
+    match causal.propagation() {
+        Some(propagation) => format!("x{} {:+.1}%", causal.total.1, 100.0 * propagation),
+        None => format!("x{}", causal.total.1),
+    }
+}
+
+pub(crate) fn dot_id(parent: &str, state: &str) -> String {
+    format!("{}/{}", parent, state)
+}
+
+fn node_label(state: &str, score: Option<f64>) -> String {
+    match score {
+        Some(score) if !analysis::is_special_state(state) => format!("{}\n({:.2})", state, score),
+        _ => state.to_string(),
+    }
+}
+
+/// White for harmless nodes, fading to red as the score reaches one
+fn fill_color(state: &str, score: Option<f64>) -> String {
+    let score = match state {
+        FAILURE_STATE => 1.0,
+        _ if analysis::is_special_state(state) => 0.0,
+        _ => score.filter(|s| s.is_finite()).unwrap_or(0.0).clamp(0.0, 1.0),
+    };
+    let shade = (255.0 * (1.0 - score)).round() as u8;
+    format!("#ff{:02x}{:02x}", shade, shade)
+}
+
+pub(crate) fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;")
+}
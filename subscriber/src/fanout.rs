@@ -0,0 +1,174 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use crate::bernoulli::BernEstimator;
+use crate::bernoulli::BernSummary;
+use crate::estimator::Estimator;
+use crate::estimator::Keyed;
+use crate::normal::CategoryNormalSummary;
+use crate::normal::NormalEstimator;
+use crate::normal::NormalSummary;
+
+
+/*
+ * Fan-out groups
+ *
+ *  Children of one parent whose lifetimes overlap, e.g. under join! or a
+ *  spawned fan-out, step the parent's chain once, into a group state
+ *  `[call_b & call_c]` listing the distinct members. How many of each ran
+ *  and how their failures relate to the parent's go to FanOutEstimator.
+ *
+ *  ContextFanOutEstimator: parent_span --> group_state --> FanOutEstimator
+ */
+
+pub const GROUP_SEPARATOR: &str = " & ";
+
+pub type ContextFanOutEstimator = Keyed<String, Keyed<String, FanOutEstimator>>;
+pub type ContextFanOutSummary = BTreeMap<String, BTreeMap<String, FanOutSummary>>;
+
+
+/// Group state standing for these distinct member states
+pub fn group_state<'a, I: IntoIterator<Item = &'a str>>(members: I) -> String {
+    let members: BTreeSet<&str> = members.into_iter().collect();
+    format!("[{}]", members.into_iter().collect::<Vec<_>>().join(GROUP_SEPARATOR))
+}
+
+/// Member states of a group state, the state itself otherwise
+pub fn group_members(state: &str) -> Vec<&str> {
+    match state.strip_prefix('[').and_then(|state| state.strip_suffix(']')) {
+        Some(members) => members.split(GROUP_SEPARATOR).collect(),
+        None => vec![state],
+    }
+}
+
+
+/// One execution of a fan-out group, kept on the parent until it closes
+#[derive(Clone, Debug)]
+pub struct FanOutGroup {
+    pub state: String,
+    /// Number of children per member state
+    pub members: BTreeMap<String, usize>,
+    /// Number of children that failed
+    pub failed: usize,
+}
+
+impl FanOutGroup {
+    /// Group of overlapping children, from their states and whether they failed
+    pub fn new<I: IntoIterator<Item = (String, bool)>>(children: I) -> FanOutGroup {
+        let mut members = BTreeMap::new();
+        let mut failed = 0;
+        for (state, failing) in children {
+            *members.entry(state).or_insert(0) += 1;
+            if failing {
+                failed += 1;
+            }
+        }
+        FanOutGroup {
+            state: group_state(members.keys().map(String::as_str)),
+            members,
+            failed,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.members.values().sum()
+    }
+}
+
+/// A fan-out group together with how its parent ended
+#[derive(Clone, Debug)]
+pub struct FanOutObservation {
+    pub group: FanOutGroup,
+    pub parent_failed: bool,
+}
+
+
+/// Shape and failures of one fan-out group under one parent span
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FanOutSummary {
+    /// Number of children running together
+    pub width: NormalSummary,
+    /// Number of children per member state
+    pub members: CategoryNormalSummary<String>,
+    /// Groups with at least one failed child, out of all groups
+    pub any_failed: BernSummary,
+    /// Groups with every child failed, out of all groups
+    pub all_failed: BernSummary,
+    /// Groups after which the parent failed, out of groups with a failed child
+    pub propagated: BernSummary,
+}
+
+
+/* Fan-out group, observing its executions */
+#[derive(Default, Clone, Debug)]
+pub struct FanOutEstimator {
+    width: NormalEstimator,
+    members: Keyed<String, NormalEstimator>,
+    any_failed: BernEstimator,
+    all_failed: BernEstimator,
+    propagated: BernEstimator,
+}
+
+impl Estimator for FanOutEstimator {
+    type Observation = FanOutObservation;
+    type Summary = FanOutSummary;
+
+    fn observe(&mut self, observation: FanOutObservation, weight: f64) {
+        let group = observation.group;
+        self.width.observe(group.width() as f64, weight);
+        self.any_failed.observe(group.failed > 0, weight);
+        self.all_failed.observe(group.failed == group.width(), weight);
+        if group.failed > 0 {
+            self.propagated.observe(observation.parent_failed, weight);
+        }
+        for (member, count) in group.members {
+            self.members.observe((member, count as f64), weight);
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.width.merge(other.width);
+        self.members.merge(other.members);
+        self.any_failed.merge(other.any_failed);
+        self.all_failed.merge(other.all_failed);
+        self.propagated.merge(other.propagated);
+    }
+
+    fn summarize(&self) -> FanOutSummary {
+        FanOutSummary {
+            width: self.width.summarize(),
+            members: self.members.summarize(),
+            any_failed: self.any_failed.summarize(),
+            all_failed: self.all_failed.summarize(),
+            propagated: self.propagated.summarize(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_state_lists_distinct_members_in_order() {
+        assert_eq!(group_state(["call_c", "call_b", "call_c"]), "[call_b & call_c]");
+        assert_eq!(group_members("[call_b & call_c]"), vec!["call_b", "call_c"]);
+        assert_eq!(group_members("call_b"), vec!["call_b"]);
+    }
+
+    #[test]
+    fn groups_count_members_and_failures() {
+        let group = FanOutGroup::new(vec![
+            ("call_b".to_string(), false),
+            ("call_c".to_string(), true),
+            ("call_b".to_string(), true),
+        ]);
+        assert_eq!(group.state, "[call_b & call_c]");
+        assert_eq!(group.members.get("call_b"), Some(&2));
+        assert_eq!(group.members.get("call_c"), Some(&1));
+        assert_eq!(group.failed, 2);
+        assert_eq!(group.width(), 3);
+    }
+}
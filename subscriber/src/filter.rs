@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use tracing::Level;
+use tracing::Metadata;
+
+
+/// Which spans participate in the dependency model
+///
+/// Spans failing the filter are collapsed: their children attach to the
+/// nearest retained ancestor and their errors count against it.
+#[derive(Default, Clone, Debug)]
+pub struct SpanFilter {
+    target_prefixes: Vec<String>,
+    denied_target_prefixes: Vec<String>,
+    max_level: Option<Level>,
+    allowed_names: BTreeSet<String>,
+    denied_names: BTreeSet<String>,
+    required_fields: Vec<String>,
+}
+
+impl SpanFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep spans whose target starts with one of the allowed prefixes
+    pub fn allow_target_prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        self.target_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Drop spans whose target starts with this prefix, e.g. `hyper` or `tokio`
+    pub fn deny_target_prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        self.denied_target_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Drop spans more verbose than `level`
+    pub fn max_level(mut self, level: Level) -> Self {
+        self.max_level = Some(level);
+        self
+    }
+
+    /// Only keep spans with one of the allowed names
+    pub fn allow_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.allowed_names.insert(name.into());
+        self
+    }
+
+    pub fn deny_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.denied_names.insert(name.into());
+        self
+    }
+
+    /// Only keep spans created with a value for this field
+    pub fn require_field<F: Into<String>>(mut self, field: F) -> Self {
+        self.required_fields.push(field.into());
+        self
+    }
+
+    /// Whether `retains` looks at span fields
+    pub fn reads_fields(&self) -> bool {
+        !self.required_fields.is_empty()
+    }
+
+    pub fn retains(&self, metadata: &Metadata<'_>, fields: &BTreeMap<&'static str, String>) -> bool {
+        let target = metadata.target();
+        let name = metadata.name();
+        (self.target_prefixes.is_empty()
+            || self.target_prefixes.iter().any(|prefix| target.starts_with(prefix.as_str())))
+        && !self.denied_target_prefixes.iter().any(|prefix| target.starts_with(prefix.as_str()))
+        && self.max_level.is_none_or(|level| *metadata.level() <= level)
+        && (self.allowed_names.is_empty() || self.allowed_names.contains(name))
+        && !self.denied_names.contains(name)
+        && self.required_fields.iter().all(|field| fields.contains_key(field.as_str()))
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use crate::analysis;
+use crate::markov::ContextMarkovSummary;
+use crate::processor::DependencySummary;
+use crate::processor::FailBernoulliSummary;
+use crate::processor::FAILURE_STATE;
+
+
+/*
+ * Influence posteriors, as in analyze.py's coruscant_analyze
+ *
+ *  InfluenceSummary: parent_span --> subspan --> P(subspan influences failure)
+ */
+pub type InfluenceSummary = BTreeMap<String, BTreeMap<String, f64>>;
+
+
+/// Influence posterior of every subspan on the failure of its parent span
+///
+/// `mu` is the Dirichlet hyperparameter weighting the Markov chain's steady
+/// failure probability against the observed conditional failure counts.
+pub fn analyze_influence(
+    span_markov: &ContextMarkovSummary<String>,
+    fail_bernoulli: &FailBernoulliSummary,
+    mu: f64,
+) -> InfluenceSummary {
+    let mut all_scores = InfluenceSummary::new();
+    for (span, chain) in span_markov.iter() {
+        let bernoulli = match fail_bernoulli.get(span) {
+            Some(bernoulli) => bernoulli,
+            None => continue,  // skip unseen failure
+        };
+        let (total_f, total) = bernoulli.total;
+        let total_s = total - total_f;
+
+        // MC(i -> F) when S and F are sinks
+        let steady_f = analysis::absorption_probs(chain, FAILURE_STATE);
+
+        let scores = all_scores.entry(span.clone()).or_default();
+        for subspan in analysis::all_states(chain) {
+            // recover counts conditioned on failing subspan
+            let (mut count_s, mut count_f) = (0, 0);
+            for (failing_subspans, (failing_then_f, count)) in bernoulli.failing_subspans.iter() {
+                if analysis::state_spans(&subspan).iter().any(|span| failing_subspans.contains(*span)) {
+                    count_s += count - failing_then_f;
+                    count_f += failing_then_f;
+                }
+            }
+
+            // dirichlet-smoothed MLE
+            let steady_f = steady_f.get(&subspan).copied().unwrap_or(0.0);
+            let (pi_f, pi_f_not) = dirichlet(
+                count_s, count_f, total_s, total_f, steady_f, mu,
+            );
+
+            // influential posterior, in log space to avoid underflow
+            let log_score_f = log_likelihood(pi_f, count_f, count_s);
+            let log_score_f_not = log_likelihood(pi_f_not, count_f, count_s);
+            scores.insert(subspan, posterior(log_score_f, log_score_f_not));
+        }
+    }
+    all_scores
+}
+
+impl DependencySummary {
+    pub fn influence(&self, mu: f64) -> InfluenceSummary {
+        analyze_influence(&self.span_markov, &self.fail_bernoulli, mu)
+    }
+}
+
+fn dirichlet(
+    count_s: usize,
+    count_f: usize,
+    total_s: usize,
+    total_f: usize,
+    steady_f: f64,
+    mu: f64,
+) -> (f64, f64) {
+    let count_all = (count_s + count_f) as f64;
+    let prob_f = (count_f as f64 + mu * steady_f) / (count_all + mu);
+    let prob_f_not = total_f as f64 / (total_s + total_f) as f64;
+    (prob_f, prob_f_not)
+}
+
+fn log_likelihood(prob: f64, count_f: usize, count_s: usize) -> f64 {
+    let log_term = |p: f64, n: usize| if n == 0 { 0.0 } else { n as f64 * p.ln() };
+    log_term(prob, count_f) + log_term(1.0 - prob, count_s)
+}
+
+fn posterior(log_score: f64, log_score_not: f64) -> f64 {
+    if log_score == f64::NEG_INFINITY && log_score_not == f64::NEG_INFINITY {
+        return f64::NAN;
+    }
+    1.0 / (1.0 + (log_score_not - log_score).exp())
+}
@@ -2,10 +2,34 @@
 pub mod record;
 
 // data processor
+pub mod estimator;
+pub mod shard;
 pub mod bernoulli;
 pub mod normal;
+pub mod fanout;
+pub mod causal;
 pub mod markov;
+pub mod mapper;
 pub mod processor;
+pub mod limits;
+pub mod custom;
 
 // subscribers and layers
-pub mod dependency;
\ No newline at end of file
+pub mod dependency;
+pub mod filter;
+pub mod sampling;
+pub mod reaper;
+mod compact;
+
+// summary analyses
+pub mod analysis;
+pub mod stats;
+pub mod anomaly;
+pub mod diff;
+pub mod sensitivity;
+pub mod influence;
+pub mod export;
+pub mod tree;
+pub mod critical;
+pub mod order;
+pub mod rootcause;
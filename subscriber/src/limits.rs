@@ -0,0 +1,465 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
+use thread_local::ThreadLocal;
+
+use crate::markov::last_state;
+use crate::processor::ABANDONED_STATE;
+use crate::processor::BUSY_SUBRECORD;
+use crate::processor::FAILURE_STATE;
+use crate::processor::IDLE_SUBRECORD;
+use crate::processor::INITIAL_STATE;
+use crate::processor::SELF_SUBRECORD;
+use crate::processor::SUCCESS_STATE;
+use crate::processor::SubspanSet;
+use crate::processor::TOTAL_SUBRECORD;
+use crate::record::ROOT_SPAN;
+
+
+/// State, context or failing subspan standing for everything past a cap
+pub const OTHER_STATE: &str = "__OTHER__";
+
+/* Rough bookkeeping cost of one key in one estimator map */
+const ENTRY_OVERHEAD_BYTES: usize = 64;
+
+
+/// Caps on distinct keys admitted into the estimator maps
+///
+/// Keys past a cap are folded into `__OTHER__`, except histories which back
+/// off to their most recent state. The crate's own `__*__` states are always
+/// admitted.
+#[derive(Clone, Copy, Debug)]
+pub struct CardinalityLimits {
+    /// Distinct parent spans
+    pub max_contexts: usize,
+    /// Distinct child states, including critical-path subrecords, per parent
+    /// span, and distinct causes of `follows_from` edges
+    pub max_states: usize,
+    /// Distinct sets of failing subspans per parent span
+    pub max_failing_sets: usize,
+    /// Distinct history states of higher-order chains per parent span
+    pub max_histories: usize,
+    /// Distinct failure origins per parent span, see `crate::processor::FailureOrigins`
+    pub max_origins: usize,
+}
+
+impl Default for CardinalityLimits {
+    fn default() -> Self {
+        CardinalityLimits {
+            max_contexts: 4096,
+            max_states: 256,
+            max_failing_sets: 64,
+            max_histories: 1024,
+            max_origins: 256,
+        }
+    }
+}
+
+impl CardinalityLimits {
+    pub fn unbounded() -> Self {
+        CardinalityLimits {
+            max_contexts: usize::MAX,
+            max_states: usize::MAX,
+            max_failing_sets: usize::MAX,
+            max_histories: usize::MAX,
+            max_origins: usize::MAX,
+        }
+    }
+
+    pub fn with_max_contexts(mut self, max_contexts: usize) -> Self {
+        self.max_contexts = max_contexts;
+        self
+    }
+
+    pub fn with_max_states(mut self, max_states: usize) -> Self {
+        self.max_states = max_states;
+        self
+    }
+
+    pub fn with_max_failing_sets(mut self, max_failing_sets: usize) -> Self {
+        self.max_failing_sets = max_failing_sets;
+        self
+    }
+
+    pub fn with_max_histories(mut self, max_histories: usize) -> Self {
+        self.max_histories = max_histories;
+        self
+    }
+
+    pub fn with_max_origins(mut self, max_origins: usize) -> Self {
+        self.max_origins = max_origins;
+        self
+    }
+}
+
+
+/// Approximate size of the estimator maps
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct MemoryUsage {
+    pub contexts: usize,
+    pub states: usize,
+    pub failing_sets: usize,
+    pub histories: usize,
+    pub origins: usize,
+    /// Observations folded into `__OTHER__` or backed off so far
+    pub overflowed: usize,
+    /// Keys and map overhead, excluding estimator counters
+    pub approx_bytes: usize,
+}
+
+
+/* Keys admitted so far out of one map, full once a key was turned away */
+#[derive(Clone, Debug)]
+struct Admitted<K> {
+    keys: HashSet<K>,
+    full: bool,
+}
+
+impl<K> Default for Admitted<K> {
+    fn default() -> Self {
+        Admitted {
+            keys: HashSet::new(),
+            full: false,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Admitted<K> {
+    /// Whether `key` is admitted, none if unknown yet
+    fn lookup(&self, key: &K) -> Option<bool> {
+        if self.keys.contains(key) {
+            Some(true)
+        } else if self.full {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Admit `key` while fewer than `max` keys are
+    fn admit(&mut self, key: &K, max: usize) -> bool {
+        if self.keys.contains(key) {
+            return true;
+        }
+        if self.keys.len() < max {
+            self.keys.insert(key.clone());
+            true
+        } else {
+            self.full = true;
+            false
+        }
+    }
+}
+
+/* Keys admitted so far, per parent span */
+#[derive(Default, Debug)]
+struct ContextKeys {
+    states: Admitted<String>,
+    failing_sets: Admitted<SubspanSet>,
+    histories: Admitted<String>,
+    origins: Admitted<String>,
+}
+
+#[derive(Default, Debug)]
+struct Keys {
+    contexts: HashMap<String, ContextKeys>,
+    /// Some context was folded, only tracked by thread caches
+    full: bool,
+}
+
+/// Folds keys past the cardinality limits into `__OTHER__`
+///
+/// Admitted sets only grow and caps never move, so every decision is final.
+/// Each thread caches the keys it has seen admitted and only takes the shared
+/// lock for keys new to it. Once a map is full the thread copies all of its
+/// keys, after which anything else is folded without locking.
+#[derive(Debug)]
+pub(crate) struct CardinalityGuard {
+    limits: CardinalityLimits,
+    keys: RwLock<Keys>,
+    cached: ThreadLocal<RefCell<Keys>>,
+    overflowed: AtomicUsize,
+    warned_contexts: AtomicBool,
+    warned_states: AtomicBool,
+    warned_failing_sets: AtomicBool,
+    warned_histories: AtomicBool,
+    warned_origins: AtomicBool,
+}
+
+impl CardinalityGuard {
+    pub(crate) fn new(limits: CardinalityLimits) -> Self {
+        CardinalityGuard {
+            limits,
+            keys: RwLock::new(Keys::default()),
+            cached: ThreadLocal::new(),
+            overflowed: AtomicUsize::new(0),
+            warned_contexts: AtomicBool::new(false),
+            warned_states: AtomicBool::new(false),
+            warned_failing_sets: AtomicBool::new(false),
+            warned_histories: AtomicBool::new(false),
+            warned_origins: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn admit_context(&self, context: String) -> String {
+        if is_reserved(&context) {
+            return context;
+        }
+        let cached = self.cached.get_or_default();
+        let known = {
+            let cached = cached.borrow();
+            match cached.contexts.contains_key(&context) {
+                true => Some(true),
+                false => cached.full.then_some(false),
+            }
+        };
+        let admitted = match known {
+            Some(admitted) => admitted,
+            None => {
+                let mut keys = self.keys.write().unwrap();
+                let admitted = keys.contexts.contains_key(&context)
+                    || keys.contexts.len() < self.limits.max_contexts;
+                let mut cached = cached.borrow_mut();
+                if admitted {
+                    keys.contexts.entry(context.clone()).or_default();
+                    cached.contexts.entry(context.clone()).or_default();
+                } else {
+                    keys.contexts.entry(OTHER_STATE.to_string()).or_default();
+                    // keys of each context are looked up again on a miss
+                    for known in keys.contexts.keys() {
+                        cached.contexts.entry(known.clone()).or_default();
+                    }
+                    cached.full = true;
+                }
+                admitted
+            },
+        };
+        if admitted {
+            context
+        } else {
+            self.overflow(&self.warned_contexts, || format!(
+                "Reached {} contexts, folding {} and later ones into {}",
+                self.limits.max_contexts, context, OTHER_STATE,
+            ));
+            OTHER_STATE.to_string()
+        }
+    }
+
+    /// Admit a child state of an already admitted context
+    pub(crate) fn admit_state(&self, context: &str, state: String) -> String {
+        if is_reserved(&state) || self.admit(context, &state, self.limits.max_states, |keys| &mut keys.states) {
+            state
+        } else {
+            self.overflow(&self.warned_states, || format!(
+                "Reached {} states under {}, folding {} and later ones into {}",
+                self.limits.max_states, context, state, OTHER_STATE,
+            ));
+            OTHER_STATE.to_string()
+        }
+    }
+
+    /// Admit a failing subspan set of an already admitted context
+    pub(crate) fn admit_failing_set(&self, context: &str, failing_set: SubspanSet) -> SubspanSet {
+        if self.admit(context, &failing_set, self.limits.max_failing_sets, |keys| &mut keys.failing_sets) {
+            failing_set
+        } else {
+            self.overflow(&self.warned_failing_sets, || format!(
+                "Reached {} failing subspan sets under {}, folding {:?} and later ones into {}",
+                self.limits.max_failing_sets, context, failing_set, OTHER_STATE,
+            ));
+            SubspanSet::from([OTHER_STATE.to_string()])
+        }
+    }
+
+    /// Admit a history state of an already admitted context, backing off to
+    /// its most recent state past the cap
+    pub(crate) fn admit_history(&self, context: &str, history: String) -> String {
+        if is_reserved(&history) || self.admit(context, &history, self.limits.max_histories, |keys| &mut keys.histories) {
+            history
+        } else {
+            self.overflow(&self.warned_histories, || format!(
+                "Reached {} histories under {}, backing {} and later ones off to first order",
+                self.limits.max_histories, context, history,
+            ));
+            last_state(&history).to_string()
+        }
+    }
+
+    /// Admit a failure origin of an already admitted context
+    pub(crate) fn admit_origin(&self, context: &str, origin: String) -> String {
+        if self.admit(context, &origin, self.limits.max_origins, |keys| &mut keys.origins) {
+            origin
+        } else {
+            self.overflow(&self.warned_origins, || format!(
+                "Reached {} failure origins under {}, folding {} and later ones into {}",
+                self.limits.max_origins, context, origin, OTHER_STATE,
+            ));
+            OTHER_STATE.to_string()
+        }
+    }
+
+    /* Cached decision if any, else the shared one, remembered for next time */
+    fn admit<K, F>(&self, context: &str, key: &K, max: usize, admitted_of: F) -> bool
+    where
+        K: Eq + Hash + Clone,
+        F: Fn(&mut ContextKeys) -> &mut Admitted<K>,
+    {
+        let cached = self.cached.get_or_default();
+        if let Some(admitted) = cached.borrow_mut()
+            .contexts
+            .get_mut(context)
+            .and_then(|keys| admitted_of(keys).lookup(key))
+        {
+            return admitted;
+        }
+        let mut keys = self.keys.write().unwrap();
+        let shared = admitted_of(keys.contexts.entry(context.to_string()).or_default());
+        let admitted = shared.admit(key, max);
+        let mut cached = cached.borrow_mut();
+        let local = admitted_of(cached.contexts.entry(context.to_string()).or_default());
+        if admitted {
+            local.keys.insert(key.clone());
+        } else {
+            *local = shared.clone();
+        }
+        admitted
+    }
+
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let keys = self.keys.read().unwrap();
+        let mut usage = MemoryUsage {
+            contexts: keys.contexts.len(),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+            ..MemoryUsage::default()
+        };
+        for (context, keys) in keys.contexts.iter() {
+            usage.states += keys.states.keys.len();
+            usage.failing_sets += keys.failing_sets.keys.len();
+            usage.histories += keys.histories.keys.len();
+            usage.origins += keys.origins.keys.len();
+            usage.approx_bytes += context.len() + ENTRY_OVERHEAD_BYTES;
+            usage.approx_bytes += keys.states.keys.iter()
+                .map(|state| state.len() + ENTRY_OVERHEAD_BYTES)
+                .sum::<usize>();
+            usage.approx_bytes += keys.failing_sets.keys.iter()
+                .map(|failing_set| failing_set.iter().map(String::len).sum::<usize>() + ENTRY_OVERHEAD_BYTES)
+                .sum::<usize>();
+            usage.approx_bytes += keys.histories.keys.iter()
+                .map(|history| history.len() + ENTRY_OVERHEAD_BYTES)
+                .sum::<usize>();
+            usage.approx_bytes += keys.origins.keys.iter()
+                .map(|origin| origin.len() + ENTRY_OVERHEAD_BYTES)
+                .sum::<usize>();
+        }
+        usage
+    }
+
+    fn overflow<F: FnOnce() -> String>(&self, warned: &AtomicBool, message: F) {
+        self.overflowed.fetch_add(1, Ordering::Relaxed);
+        if !warned.swap(true, Ordering::Relaxed) {
+            log::warn!("{}", message());
+        }
+    }
+}
+
+/* States the crate itself uses bypass the caps, whatever the user names spans */
+fn is_reserved(state: &str) -> bool {
+    [
+        INITIAL_STATE,
+        SUCCESS_STATE,
+        FAILURE_STATE,
+        ABANDONED_STATE,
+        TOTAL_SUBRECORD,
+        SELF_SUBRECORD,
+        BUSY_SUBRECORD,
+        IDLE_SUBRECORD,
+        ROOT_SPAN,
+        OTHER_STATE,
+    ].contains(&state)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> CardinalityLimits {
+        CardinalityLimits::default()
+            .with_max_contexts(2)
+            .with_max_states(2)
+            .with_max_failing_sets(1)
+            .with_max_histories(1)
+            .with_max_origins(1)
+    }
+
+    #[test]
+    fn states_past_the_cap_fold_into_other() {
+        let guard = CardinalityGuard::new(limits());
+        assert_eq!(guard.admit_state("call_a", "call_b".to_string()), "call_b");
+        assert_eq!(guard.admit_state("call_a", "call_c".to_string()), "call_c");
+        assert_eq!(guard.admit_state("call_a", "call_d".to_string()), OTHER_STATE);
+        // admitted keys stay admitted, other contexts have their own cap
+        assert_eq!(guard.admit_state("call_a", "call_b".to_string()), "call_b");
+        assert_eq!(guard.admit_state("call_b", "call_d".to_string()), "call_d");
+        // the crate's states pass, spans merely named like them do not
+        assert_eq!(guard.admit_state("call_a", FAILURE_STATE.to_string()), FAILURE_STATE);
+        assert_eq!(guard.admit_state("call_a", "__call_e__".to_string()), OTHER_STATE);
+        assert_eq!(guard.memory_usage().overflowed, 2);
+    }
+
+    #[test]
+    fn contexts_past_the_cap_fold_into_other() {
+        let guard = CardinalityGuard::new(limits());
+        assert_eq!(guard.admit_context("call_a".to_string()), "call_a");
+        assert_eq!(guard.admit_context("call_b".to_string()), "call_b");
+        assert_eq!(guard.admit_context("call_c".to_string()), OTHER_STATE);
+        assert_eq!(guard.admit_context(ROOT_SPAN.to_string()), ROOT_SPAN);
+        assert_eq!(guard.admit_context("call_a".to_string()), "call_a");
+    }
+
+    #[test]
+    fn histories_past_the_cap_back_off() {
+        let guard = CardinalityGuard::new(limits());
+        assert_eq!(guard.admit_history("call_a", "call_b > call_c".to_string()), "call_b > call_c");
+        assert_eq!(guard.admit_history("call_a", "call_c > call_c".to_string()), "call_c");
+        let failing_set = SubspanSet::from(["call_b".to_string()]);
+        assert_eq!(guard.admit_failing_set("call_a", failing_set.clone()), failing_set);
+        assert_eq!(
+            guard.admit_failing_set("call_a", SubspanSet::new()),
+            SubspanSet::from([OTHER_STATE.to_string()]),
+        );
+    }
+
+    #[test]
+    fn origins_have_their_own_cap() {
+        let guard = CardinalityGuard::new(limits());
+        assert_eq!(guard.admit_state("call_a", "call_b".to_string()), "call_b");
+        assert_eq!(guard.admit_origin("call_a", "call_b / call_d".to_string()), "call_b / call_d");
+        assert_eq!(guard.admit_origin("call_a", "call_b / call_e".to_string()), OTHER_STATE);
+        // origins leave room for states
+        assert_eq!(guard.admit_state("call_a", "call_c".to_string()), "call_c");
+        assert_eq!(guard.memory_usage().origins, 1);
+    }
+
+    #[test]
+    fn threads_share_decisions() {
+        let guard = CardinalityGuard::new(limits());
+        assert_eq!(guard.admit_state("call_a", "call_b".to_string()), "call_b");
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                assert_eq!(guard.admit_state("call_a", "call_c".to_string()), "call_c");
+                assert_eq!(guard.admit_state("call_a", "call_b".to_string()), "call_b");
+            });
+        });
+        // this thread learns call_c along with the cap
+        assert_eq!(guard.admit_state("call_a", "call_d".to_string()), OTHER_STATE);
+        assert_eq!(guard.admit_state("call_a", "call_c".to_string()), "call_c");
+        assert_eq!(guard.memory_usage().states, 2);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::record::SpanRecord;
+
+
+/// Decides which state a span record becomes in the dependency model
+///
+/// A span shows up in two roles: as a state in its parent's chain, and as
+/// the context keying its own chain and statistics. The context is looked up
+/// while the span is still open, so it must not depend on how the span ends.
+pub trait StateMapper: Send + Sync {
+    /// State of a span inside its parent's chain
+    fn map_state(&self, sr: &SpanRecord) -> String;
+
+    /// Key of a span as a parent of other spans
+    fn map_context(&self, sr: &SpanRecord) -> String {
+        self.map_state(sr)
+    }
+
+    /// Whether states depend on `SpanRecord::fields`, which are only
+    /// captured if some mapper, filter or custom estimator reads them
+    fn reads_fields(&self) -> bool {
+        false
+    }
+}
+
+
+/* Span name only, merging every call site and field value */
+#[derive(Default, Clone, Debug)]
+pub struct NameMapper;
+
+impl StateMapper for NameMapper {
+    fn map_state(&self, sr: &SpanRecord) -> String {
+        sr.name.to_string()
+    }
+}
+
+
+/* Span name qualified by its target, e.g. `my_crate::net::send` */
+#[derive(Default, Clone, Debug)]
+pub struct TargetMapper;
+
+impl StateMapper for TargetMapper {
+    fn map_state(&self, sr: &SpanRecord) -> String {
+        Qualifier::Target.qualify(sr)
+    }
+}
+
+
+/// Which part of the declaration site qualifies a span name
+#[derive(Default, Clone, Copy, Debug)]
+pub enum Qualifier {
+    /// `my_crate::net::send`
+    #[default]
+    Target,
+    /// `my_crate::net::send`, from the module path even if the target is overridden
+    ModulePath,
+    /// `send@src/net.rs`
+    File,
+    /// `send@src/net.rs:42`
+    FileLine,
+}
+
+impl Qualifier {
+    pub fn qualify(&self, sr: &SpanRecord) -> String {
+        let qualified = match self {
+            Qualifier::Target => Some(sr.target)
+                .filter(|target| !target.is_empty())
+                .map(|target| format!("{}::{}", target, sr.name)),
+            Qualifier::ModulePath => sr.module_path
+                .map(|module_path| format!("{}::{}", module_path, sr.name)),
+            Qualifier::File => sr.file
+                .map(|file| format!("{}@{}", sr.name, file)),
+            Qualifier::FileLine => sr.file
+                .map(|file| match sr.line {
+                    Some(line) => format!("{}@{}:{}", sr.name, file, line),
+                    None => format!("{}@{}", sr.name, file),
+                }),
+        };
+        qualified.unwrap_or_else(|| sr.name.to_string())
+    }
+}
+
+
+/* Span name qualified by a configurable part of its declaration site */
+#[derive(Default, Clone, Debug)]
+pub struct QualifiedMapper {
+    qualifier: Qualifier,
+}
+
+impl QualifiedMapper {
+    pub fn new(qualifier: Qualifier) -> Self {
+        QualifiedMapper { qualifier }
+    }
+}
+
+impl StateMapper for QualifiedMapper {
+    fn map_state(&self, sr: &SpanRecord) -> String {
+        self.qualifier.qualify(sr)
+    }
+}
+
+
+/* Span name broken down by selected fields, e.g. `call_rpc{peer=node3}` */
+#[derive(Default, Clone, Debug)]
+pub struct FieldsMapper {
+    fields: Vec<String>,
+}
+
+impl FieldsMapper {
+    pub fn new<I, F>(fields: I) -> Self
+    where
+        I: IntoIterator<Item = F>,
+        F: Into<String>,
+    {
+        FieldsMapper {
+            fields: fields.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl StateMapper for FieldsMapper {
+    fn map_state(&self, sr: &SpanRecord) -> String {
+        let key_values: Vec<String> = self.fields.iter()
+            .filter_map(|field| {
+                sr.fields.get(field.as_str()).map(|value| format!("{}={}", field, value))
+            })
+            .collect();
+        if key_values.is_empty() {
+            sr.name.to_string()
+        } else {
+            format!("{}{{{}}}", sr.name, key_values.join(" "))
+        }
+    }
+
+    fn reads_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+}
+
+
+/* Failing spans become their own `[FAIL]` states, contexts stay untagged */
+#[derive(Default, Clone, Debug)]
+pub struct FailureTaggedMapper<M> {
+    inner: M,
+}
+
+impl<M: StateMapper> FailureTaggedMapper<M> {
+    pub fn new(inner: M) -> Self {
+        FailureTaggedMapper { inner }
+    }
+}
+
+impl<M: StateMapper> StateMapper for FailureTaggedMapper<M> {
+    fn map_state(&self, sr: &SpanRecord) -> String {
+        if sr.failing {
+            format!("{} [FAIL]", self.inner.map_state(sr))
+        } else {
+            self.inner.map_state(sr)
+        }
+    }
+
+    fn map_context(&self, sr: &SpanRecord) -> String {
+        self.inner.map_context(sr)
+    }
+
+    fn reads_fields(&self) -> bool {
+        self.inner.reads_fields()
+    }
+}
@@ -1,7 +1,13 @@
-use chashmap::CHashMap;
+use serde::Deserialize;
+use serde::Serialize;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::hash::Hash;
 
+use crate::estimator::Estimator;
+use crate::estimator::Keyed;
+use crate::shard::Sharded;
+
 
 /*
  * Model hierarchy
@@ -9,88 +15,188 @@ use std::hash::Hash;
  *  ContextMarkovEstimator: parent_span --> MarkovEstimator
  *  MarkovEstimator: current_span --> StateEstimator
  *  StateEstimator: next_span --> probability
+ *
+ * Each thread counts into its own shard, drained when summarized.
+ *
+ * Higher-order chains reuse the same model over history states, the last
+ * k child states joined by HISTORY_SEPARATOR, e.g. `call_c > call_c`.
+ * Terminal states stay bare, so a history chain is an absorbing chain in
+ * its own right and the analyses read it like a first-order one.
  */
 
-type StateSummary<T> = BTreeMap<T, f64>;
-type MarkovSummary<T> = BTreeMap<T, StateSummary<T>>;
+pub const HISTORY_SEPARATOR: &str = " > ";
+
+pub type StateSummary<T> = BTreeMap<T, f64>;
+pub type MarkovSummary<T> = BTreeMap<T, StateSummary<T>>;
 pub type ContextMarkovSummary<T> = BTreeMap<T, MarkovSummary<T>>;
+/// Number of transitions out of every state, turning probabilities back into counts
+pub type ContextCountSummary<T> = BTreeMap<T, BTreeMap<T, usize>>;
+
+/// Chains over the last `order` child states of every parent span
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HistoryMarkovSummary {
+    pub order: usize,
+    pub chains: ContextMarkovSummary<String>,
+}
+
+/// Everything a context Markov estimator counted, summarized together
+#[derive(Clone, Debug, Default)]
+pub struct ContextMarkovTotals<T: Ord> {
+    pub chains: ContextMarkovSummary<T>,
+    pub counts: ContextCountSummary<T>,
+    /// One per observation, whatever its weight
+    pub samples: ContextCountSummary<T>,
+}
+
+/// History state standing for `states`, oldest first
+pub fn history_state<'a, I: IntoIterator<Item = &'a str>>(states: I) -> String {
+    states.into_iter().collect::<Vec<_>>().join(HISTORY_SEPARATOR)
+}
+
+/// Most recent child state of a history state, the state itself otherwise
+pub fn last_state(state: &str) -> &str {
+    state.rsplit(HISTORY_SEPARATOR).next().unwrap_or(state)
+}
+
+/* Transition model between multiple states */
+pub type MarkovEstimator<T> = Keyed<T, StateEstimator<T>>;
 
 
 /* Single-state transition model */
-#[derive(Default, Clone, Debug)]
-struct StateEstimator<T> {
-    state_count: usize,
-    transition_count: CHashMap<T, usize>
+#[derive(Clone, Debug)]
+pub struct StateEstimator<T> {
+    state_count: f64,
+    state_samples: usize,
+    transition_count: HashMap<T, f64>
 }
 
-// impl<T: PartialEq + Eq + Hash + Default> StateEstimator<T> {
-impl<T: PartialEq + Eq + Hash + Ord + Default> StateEstimator<T> {
-    fn observe(&mut self, next_state: T) {
-        self.state_count += 1;
-        self.transition_count.upsert(
-            next_state,
-            || 1,
-            |tc|
-            *tc += 1,
-        );
+impl<T> Default for StateEstimator<T> {
+    fn default() -> Self {
+        StateEstimator {
+            state_count: 0.0,
+            state_samples: 0,
+            transition_count: HashMap::new(),
+        }
     }
+}
 
-    fn summarize(self) -> StateSummary<T> {
-        self.transition_count.into_iter()
-            .map(|(next_state, count)|
-                (next_state, count as f64 / self.state_count as f64))
-            .collect()
+impl<T> StateEstimator<T> {
+    /// Number of transitions observed out of this state
+    pub fn count(&self) -> f64 {
+        self.state_count
+    }
+
+    /// Number of transitions recorded out of this state, regardless of their weights
+    pub fn samples(&self) -> usize {
+        self.state_samples
     }
 }
 
+impl<T: Eq + Hash + Ord + Clone + Send> Estimator for StateEstimator<T> {
+    type Observation = T;
+    type Summary = StateSummary<T>;
 
-/* Transition model between multiple states */
-#[derive(Default, Clone, Debug)]
-struct MarkovEstimator<T> {
-    state_models: CHashMap<T, StateEstimator<T>>
-}
+    fn observe(&mut self, next_state: T, weight: f64) {
+        self.state_count += weight;
+        self.state_samples += 1;
+        *self.transition_count.entry(next_state).or_insert(0.0) += weight;
+    }
 
-// impl<T: PartialEq + Eq + Hash + Default> MarkovEstimator<T> {
-impl<T: PartialEq + Eq + Hash + Ord + Default> MarkovEstimator<T> {
-    fn observe(&self, state: T, next_state: T) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe(next_state)
-        } else {
-            let mut sm = StateEstimator::default();
-            sm.observe(next_state);
-            self.state_models.insert_new(state, sm)
+    fn merge(&mut self, other: Self) {
+        self.state_count += other.state_count;
+        self.state_samples += other.state_samples;
+        for (next_state, count) in other.transition_count {
+            *self.transition_count.entry(next_state).or_insert(0.0) += count;
         }
     }
 
-    fn summarize(self) -> MarkovSummary<T> {
-        self.state_models.into_iter()
-            .map(|(context, sm)| (context, sm.summarize()))
+    fn summarize(&self) -> StateSummary<T> {
+        self.transition_count.iter()
+            .map(|(next_state, count)|
+                (next_state.clone(), count / self.state_count))
             .collect()
     }
 }
 
 
-/* Transition model between multiple states */
-#[derive(Default, Clone, Debug)]
-pub struct ContextMarkovEstimator<T> {
-    span_models: CHashMap<T, MarkovEstimator<T>>
+/* Transition model between multiple states, per parent span */
+#[derive(Debug)]
+pub struct ContextMarkovEstimator<T: Eq + Hash + Ord + Clone + Send> {
+    span_models: Sharded<Keyed<T, MarkovEstimator<T>>>
 }
 
-// impl<T: PartialEq + Eq + Hash + Default> ContextMarkovEstimator<T> {
-impl<T: PartialEq + Eq + Hash + Ord + Default> ContextMarkovEstimator<T> {
-    pub fn observe(&self, context: T, state: T, next_state: T) {
-        if self.span_models.contains_key(&context) {
-            self.span_models.get_mut(&context).unwrap().observe(state, next_state)
-        } else {
-            let mm = MarkovEstimator::default();
-            mm.observe(state, next_state);
-            self.span_models.insert_new(context, mm)
+impl<T: Eq + Hash + Ord + Clone + Send> Default for ContextMarkovEstimator<T> {
+    fn default() -> Self {
+        ContextMarkovEstimator {
+            span_models: Sharded::default(),
         }
     }
+}
 
-    pub fn summarize(self) -> ContextMarkovSummary<T> {
-        self.span_models.into_iter()
-            .map(|(context, mm)| (context, mm.summarize()))
-            .collect()
+impl<T: Eq + Hash + Ord + Clone + Send> ContextMarkovEstimator<T> {
+    pub fn observe(&self, context: T, state: T, next_state: T) {
+        self.observe_weighted(context, state, next_state, 1.0)
+    }
+
+    /// Observe a transition standing for `weight` transitions, e.g. 1/p under sampling
+    pub fn observe_weighted(&self, context: T, state: T, next_state: T, weight: f64) {
+        self.span_models.observe((context, (state, next_state)), weight)
+    }
+
+    /// Summary of everything observed so far, leaving the counts in place
+    pub fn summarize(&self) -> ContextMarkovSummary<T> {
+        self.span_models.summarize()
+    }
+
+    /// Summary along with transition counts and samples out of every state,
+    /// drained from the shards once
+    pub fn summarize_totals(&self) -> ContextMarkovTotals<T> {
+        self.span_models.with_total(|span_models| {
+            let mut counts = ContextCountSummary::new();
+            let mut samples = ContextCountSummary::new();
+            for (context, model) in span_models.iter() {
+                counts.insert(context.clone(), model.iter()
+                    .map(|(state, state_model)| (state.clone(), state_model.count().round() as usize))
+                    .collect());
+                samples.insert(context.clone(), model.iter()
+                    .map(|(state, state_model)| (state.clone(), state_model.samples()))
+                    .collect());
+            }
+            ContextMarkovTotals {
+                chains: span_models.summarize(),
+                counts,
+                samples,
+            }
+        })
+    }
+
+    pub fn reset(&self) {
+        self.span_models.reset()
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_states_end_in_their_last_state() {
+        let history = history_state(["call_b", "call_c", "call_c"]);
+        assert_eq!(history, "call_b > call_c > call_c");
+        assert_eq!(last_state(&history), "call_c");
+        assert_eq!(last_state("call_b"), "call_b");
+    }
+
+    #[test]
+    fn counts_and_samples_of_weighted_transitions() {
+        let estimator = ContextMarkovEstimator::default();
+        estimator.observe_weighted("call_a", "call_b", "call_c", 4.0);
+        estimator.observe("call_a", "call_b", "call_d");
+        assert_eq!(estimator.summarize()["call_a"]["call_b"]["call_c"], 0.8);
+        let totals = estimator.summarize_totals();
+        assert_eq!(totals.chains["call_a"]["call_b"]["call_c"], 0.8);
+        assert_eq!(totals.counts["call_a"]["call_b"], 5);
+        assert_eq!(totals.samples["call_a"]["call_b"], 2);
+    }
+}
@@ -1,16 +1,19 @@
 use std::collections::BTreeMap;
-use chashmap::CHashMap;
 use std::hash::Hash;
 
+use crate::estimator::Estimator;
+use crate::estimator::Keyed;
+use crate::shard::Sharded;
 
-type NormalSummary = (f64, f64);
-type CategoryNormalSummary<T> = BTreeMap<T, NormalSummary>;
+
+pub type NormalSummary = (f64, f64, usize);
+pub type CategoryNormalSummary<T> = BTreeMap<T, NormalSummary>;
 pub type ManyCategoryNormalSummary<T> = BTreeMap<T, CategoryNormalSummary<T>>;
 
 
-/* Bernoulli */
+/* Normal, observing a number */
 #[derive(Clone, Debug)]
-struct NormalEstimator {
+pub struct NormalEstimator {
     sum: f64,
     square_sum: f64,
     count: f64,
@@ -26,72 +29,108 @@ impl Default for NormalEstimator {
     }
 }
 
-impl NormalEstimator {
-    fn observe(&mut self, number: f64) {
+impl Estimator for NormalEstimator {
+    type Observation = f64;
+    type Summary = NormalSummary;
+
+    fn observe(&mut self, number: f64, weight: f64) {
         // log::error!("{}", number);
-        self.count += 1.0;
-        self.sum += number;
-        self.square_sum += number * number;
+        self.count += weight;
+        self.sum += weight * number;
+        self.square_sum += weight * number * number;
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.square_sum += other.square_sum;
     }
 
-    fn summarize(self) -> NormalSummary {
+    fn summarize(&self) -> NormalSummary {
         (
             self.sum / self.count,  // mean
-            (self.square_sum / self.count - (self.sum / self.count).powi(2)).sqrt(),  // stddev
+            (self.square_sum / self.count - (self.sum / self.count).powi(2)).max(0.0).sqrt(),  // stddev
+            self.count.round() as usize,  // count
         )
     }
 }
 
 
-/* Multiple Bernoulli  */
-#[derive(Default, Clone, Debug)]
-pub struct CategoryNormalEstimator<T> {
-    state_models: CHashMap<T, NormalEstimator>
+/* Multiple Normal, sharded per thread */
+#[derive(Debug)]
+pub struct CategoryNormalEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
+{
+    state_models: Sharded<Keyed<T, NormalEstimator>>
 }
 
-impl<T> CategoryNormalEstimator<T> 
-where T: PartialEq + Eq + Hash + Ord + Default + std::fmt::Debug
+impl<T> Default for CategoryNormalEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
 {
-    pub fn observe(&self, state: T, number: f64) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe(number)
-        } else {
-            let mut bm = NormalEstimator::default();
-            bm.observe(number);
-            self.state_models.insert_new(state, bm)
+    fn default() -> Self {
+        CategoryNormalEstimator {
+            state_models: Sharded::default(),
         }
     }
+}
+
+impl<T> CategoryNormalEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
+{
+    pub fn observe(&self, state: T, number: f64) {
+        self.observe_weighted(state, number, 1.0)
+    }
+
+    pub fn observe_weighted(&self, state: T, number: f64, weight: f64) {
+        self.state_models.observe((state, number), weight)
+    }
 
-    pub fn summarize(self) -> CategoryNormalSummary<T> {
-        self.state_models.into_iter()
-            .map(|(context, bm)| (context, bm.summarize()))
-            .collect()
+    /// Summary of everything observed so far, leaving the counts in place
+    pub fn summarize(&self) -> CategoryNormalSummary<T> {
+        self.state_models.summarize()
+    }
+
+    pub fn reset(&self) {
+        self.state_models.reset()
     }
 }
 
 
-/* Many Multiple Bernoulli */
-#[derive(Default, Clone, Debug)]
-pub struct ManyCategoryNormalEstimator<T> {
-    state_models: CHashMap<T, CategoryNormalEstimator<T>>
+/* Many Multiple Normal, sharded per thread */
+#[derive(Debug)]
+pub struct ManyCategoryNormalEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
+{
+    state_models: Sharded<Keyed<T, Keyed<T, NormalEstimator>>>
 }
 
-impl<T> ManyCategoryNormalEstimator<T> 
-where T: PartialEq + Eq + Hash + Ord + Default + std::fmt::Debug
+impl<T> Default for ManyCategoryNormalEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
 {
-    pub fn observe(&self, state: T, substate: T, number: f64) {
-        if self.state_models.contains_key(&state) {
-            self.state_models.get_mut(&state).unwrap().observe(substate, number)
-        } else {
-            let cbm = CategoryNormalEstimator::default();
-            cbm.observe(substate, number);
-            self.state_models.insert_new(state, cbm)
+    fn default() -> Self {
+        ManyCategoryNormalEstimator {
+            state_models: Sharded::default(),
         }
     }
+}
+
+impl<T> ManyCategoryNormalEstimator<T>
+where T: Eq + Hash + Ord + Clone + Send
+{
+    pub fn observe(&self, state: T, substate: T, number: f64) {
+        self.observe_weighted(state, substate, number, 1.0)
+    }
+
+    pub fn observe_weighted(&self, state: T, substate: T, number: f64, weight: f64) {
+        self.state_models.observe((state, (substate, number)), weight)
+    }
+
+    /// Summary of everything observed so far, leaving the counts in place
+    pub fn summarize(&self) -> ManyCategoryNormalSummary<T> {
+        self.state_models.summarize()
+    }
 
-    pub fn summarize(self) -> ManyCategoryNormalSummary<T> {
-        self.state_models.into_iter()
-            .map(|(context, cbm)| (context, cbm.summarize()))
-            .collect()
+    pub fn reset(&self) {
+        self.state_models.reset()
     }
 }
@@ -0,0 +1,152 @@
+use serde::Serialize;
+
+use crate::analysis;
+use crate::markov::MarkovSummary;
+use crate::processor::DependencySummary;
+use crate::processor::INITIAL_STATE;
+use crate::record::ROOT_SPAN;
+
+
+/// How well one chain explains a parent span's child sequences
+#[derive(Serialize, Clone, Debug)]
+pub struct ChainFit {
+    pub order: usize,
+    /// Mean log-likelihood of one execution's child sequence, in nats
+    pub log_likelihood: f64,
+    /// Free transition probabilities
+    pub parameters: usize,
+    /// AIC estimate of the mean log-likelihood of an unseen execution
+    pub predictive: f64,
+}
+
+/// First-order against higher-order chain of one parent span
+#[derive(Serialize, Clone, Debug)]
+pub struct OrderComparison {
+    pub span: String,
+    pub executions: usize,
+    pub first_order: ChainFit,
+    pub history: ChainFit,
+}
+
+impl OrderComparison {
+    /// Predictive log-likelihood per execution gained by the higher order
+    pub fn gain(&self) -> f64 {
+        self.history.predictive - self.first_order.predictive
+    }
+}
+
+
+/*
+ * Predictive likelihood of first-order vs k-th order chains
+ *
+ *  Both chains are maximum likelihood fits, so the expected visits to each
+ *  state per execution are the observed ones and the mean log-likelihood of
+ *  an execution is sum_s visits(s) * sum_n P(n|s) ln P(n|s). In-sample, the
+ *  higher order always wins; subtracting parameters / executions (AIC)
+ *  estimates how it would do on executions it has not seen.
+ */
+
+/// Compare chains of every parent span, largest predictive gain first
+///
+/// Empty unless the processor fitted higher-order chains.
+pub fn compare_orders(summary: &DependencySummary) -> Vec<OrderComparison> {
+    let history_markov = match &summary.history_markov {
+        Some(history_markov) => history_markov,
+        None => return Vec::new(),
+    };
+    let mut comparisons: Vec<OrderComparison> = summary.span_markov.iter()
+        .filter(|(span, _)| span.as_str() != ROOT_SPAN)
+        .filter_map(|(span, chain)| {
+            let history_chain = history_markov.chains.get(span)?;
+            let executions = summary.fail_bernoulli.get(span)
+                .map(|fail_summary| fail_summary.total.1)
+                .filter(|executions| *executions > 0)?;
+            Some(OrderComparison {
+                span: span.clone(),
+                executions,
+                first_order: fit(chain, 1, executions),
+                history: fit(history_chain, history_markov.order, executions),
+            })
+        })
+        .collect();
+    comparisons.sort_by(|a, b| b.gain().total_cmp(&a.gain()).then(a.span.cmp(&b.span)));
+    comparisons
+}
+
+fn fit(chain: &MarkovSummary<String>, order: usize, executions: usize) -> ChainFit {
+    let visits = analysis::expected_visits(chain, INITIAL_STATE);
+    let log_likelihood: f64 = chain.iter()
+        .map(|(state, edges)| {
+            let log_prob: f64 = edges.values()
+                .filter(|prob| **prob > 0.0)
+                .map(|prob| prob * prob.ln())
+                .sum();
+            visits.get(state).copied().unwrap_or(0.0) * log_prob
+        })
+        .sum();
+    let parameters = chain.values()
+        .map(|edges| edges.len().saturating_sub(1))
+        .sum();
+    ChainFit {
+        order,
+        log_likelihood,
+        parameters,
+        predictive: log_likelihood - parameters as f64 / executions as f64,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov::history_state;
+    use crate::processor::SUCCESS_STATE;
+
+    /* call_a runs call_c exactly four times, which only the order-4 chain knows */
+    fn summary() -> DependencySummary {
+        let histories: Vec<String> = (1..=4)
+            .map(|count| history_state(std::iter::repeat_n("call_c", count)))
+            .collect();
+        let mut history_chain = serde_json::json!({
+            INITIAL_STATE: { &histories[0]: 1.0 },
+            &histories[3]: { SUCCESS_STATE: 1.0 },
+        });
+        for pair in histories.windows(2) {
+            history_chain[&pair[0]] = serde_json::json!({ &pair[1]: 1.0 });
+        }
+        serde_json::from_value(serde_json::json!({
+            "span_markov": {
+                "call_a": {
+                    INITIAL_STATE: { "call_c": 1.0 },
+                    "call_c": { "call_c": 0.75, SUCCESS_STATE: 0.25 },
+                },
+            },
+            "history_markov": { "order": 4, "chains": { "call_a": history_chain } },
+            "fail_bernoulli": {
+                "call_a": { "total": [0, 100], "failing_subspans": [] },
+            },
+        })).unwrap()
+    }
+
+    #[test]
+    fn higher_order_wins_on_fixed_repeats() {
+        let comparisons = compare_orders(&summary());
+        assert_eq!(comparisons.len(), 1);
+        let comparison = &comparisons[0];
+        assert_eq!(comparison.executions, 100);
+        // four visits of call_c, each with entropy of a 3/4 coin
+        let entropy = -(0.75f64 * 0.75f64.ln() + 0.25 * 0.25f64.ln());
+        assert!((comparison.first_order.log_likelihood + 4.0 * entropy).abs() < 1e-9);
+        assert_eq!(comparison.first_order.parameters, 1);
+        assert!(comparison.history.log_likelihood.abs() < 1e-9);
+        assert_eq!(comparison.history.parameters, 0);
+        assert!((comparison.gain() - (4.0 * entropy + 0.01)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn no_comparison_without_history_chains() {
+        let mut summary = summary();
+        summary.history_markov = None;
+        assert!(compare_orders(&summary).is_empty());
+    }
+}
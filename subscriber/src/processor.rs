@@ -1,44 +1,274 @@
-use itertools::Itertools;
+use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::Write;
+use std::fs::File;
 use std::fs::OpenOptions;
 use std::time::Duration;
+use std::time::Instant;
 use std::sync::Arc;
+use std::sync::RwLock;
+use tracing::Metadata;
 
+use crate::bernoulli::BernSummary;
+use crate::bernoulli::CategoryBernEstimator;
+use crate::bernoulli::CategoryBernSummary;
 use crate::bernoulli::ManyCategoryBernEstimator;
 use crate::bernoulli::ManyCategoryBernSummary;
-// use crate::normal::ManyCategoryNormalEstimator;
-// use crate::normal::ManyCategoryNormalSummary;
+use crate::normal::ManyCategoryNormalEstimator;
+use crate::normal::ManyCategoryNormalSummary;
+use crate::causal::CAUSES_CONTEXT;
+use crate::causal::CausalObservation;
+use crate::causal::ContextCausalEstimator;
+use crate::causal::ContextCausalSummary;
+use crate::custom::CustomEstimator;
+use crate::fanout::ContextFanOutEstimator;
+use crate::fanout::ContextFanOutSummary;
+use crate::fanout::FanOutGroup;
+use crate::fanout::FanOutObservation;
+use crate::custom::CustomObservation;
+use crate::custom::ObservationSource;
+use crate::limits::CardinalityGuard;
+use crate::limits::CardinalityLimits;
+use crate::limits::MemoryUsage;
+use crate::markov::ContextCountSummary;
 use crate::markov::ContextMarkovEstimator;
 use crate::markov::ContextMarkovSummary;
+use crate::markov::HistoryMarkovSummary;
+use crate::markov::history_state;
+use crate::mapper::FieldsMapper;
+use crate::mapper::NameMapper;
+use crate::mapper::StateMapper;
+use crate::reaper::OpenSpanStats;
+use crate::shard::Sharded;
+use crate::record::Callsite;
+use crate::record::PendingChild;
+use crate::record::SpanKey;
 use crate::record::SpanRecord;
+use crate::sampling::TailDecision;
+use crate::sampling::TraceHandle;
 
 
-const INITIAL_STATE: &str = "__INITIAL_STATE__";
-const SUCCESS_STATE: &str = "__SUCCESS_STATE__";
-const FAILURE_STATE: &str = "__FAILURE_STATE__";
-const TOTAL_SUBRECORD: &str = "__TOTAL__";
+pub const INITIAL_STATE: &str = "__INITIAL_STATE__";
+pub const SUCCESS_STATE: &str = "__SUCCESS_STATE__";
+pub const FAILURE_STATE: &str = "__FAILURE_STATE__";
+pub const ABANDONED_STATE: &str = "__ABANDONED__";
+pub const TOTAL_SUBRECORD: &str = "__TOTAL__";
+pub const SELF_SUBRECORD: &str = "__SELF__";
+pub const BUSY_SUBRECORD: &str = "__BUSY__";
+pub const IDLE_SUBRECORD: &str = "__IDLE__";
+pub const ORIGIN_SEPARATOR: &str = " / ";
 const WRITE_PERIOD: Duration = Duration::from_secs(10);
 const DEPENDENCY_OUT: &str = "dependency_summary.jsons";
 
-#[derive(Serialize, Debug)]
+/// Set of child states that failed during one execution of a parent span
+pub type SubspanSet = BTreeSet<String>;
+
+/// Failures of a parent span, overall and per set of failing child states
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(from = "FailSummaryShape")]
+pub struct FailSummary {
+    pub total: BernSummary,
+    pub failing_subspans: Vec<(SubspanSet, BernSummary)>,
+    /// Failures and executions actually recorded, `total` before sampling weights
+    pub samples: BernSummary,
+}
+
+/* Summaries written before `FailSummary` keyed each parent's counts by its
+ * failing child states joined with ", ", next to a `__TOTAL__` entry */
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FailSummaryShape {
+    Current {
+        total: BernSummary,
+        failing_subspans: Vec<(SubspanSet, BernSummary)>,
+        samples: Option<BernSummary>,
+    },
+    Joined(CategoryBernSummary<String>),
+}
+
+impl From<FailSummaryShape> for FailSummary {
+    fn from(shape: FailSummaryShape) -> Self {
+        match shape {
+            FailSummaryShape::Current { total, failing_subspans, samples } => FailSummary {
+                total,
+                failing_subspans,
+                // written before samples were kept apart
+                samples: samples.unwrap_or(total),
+            },
+            FailSummaryShape::Joined(mut counts) => {
+                let total = counts.remove(TOTAL_SUBRECORD).unwrap_or_default();
+                let failing_subspans = counts.into_iter()
+                    .map(|(joined, counts)| {
+                        let subspans = joined.split(", ")
+                            .filter(|state| !state.is_empty())
+                            .map(str::to_string)
+                            .collect();
+                        (subspans, counts)
+                    })
+                    .collect();
+                // written before sampling
+                FailSummary { total, failing_subspans, samples: total }
+            },
+        }
+    }
+}
+
+pub type FailBernoulliSummary = BTreeMap<String, FailSummary>;
+
+/// How far failures are traced below their parent span
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureOrigins {
+    /// Only direct children, in `failing_subspans`
+    #[default]
+    Off,
+    /// Deepest failing descendant each failure came from
+    Leaf,
+    /// Failing spans down to that descendant, e.g. `call_b / call_d`
+    Path,
+}
+
+/// A single input to the estimators, already mapped to states
+#[derive(Clone, Debug)]
+pub enum Observation {
+    Transition { context: String, state: String, next_state: String },
+    History { context: String, history: String, next_history: String },
+    FanOut { context: String, fan_out: FanOutObservation },
+    Causal { cause: String, effect: String, causal: CausalObservation },
+    Fail { context: String, failing_subspans: SubspanSet, failed: bool },
+    Origins { context: String, origins: SubspanSet, failed: bool },
+    Time { context: String, subrecord: String, seconds: f64 },
+    Custom(CustomObservation),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct DependencySummary {
-    span_markov: ContextMarkovSummary<String>,
-    fail_bernoulli: ManyCategoryBernSummary<String>,
+    pub(crate) span_markov: ContextMarkovSummary<String>,
+    /// Transitions out of every state of `span_markov`
+    #[serde(default)]
+    pub(crate) state_counts: ContextCountSummary<String>,
+    /// Transitions recorded out of every state, `state_counts` before sampling weights
+    #[serde(default)]
+    pub(crate) state_samples: ContextCountSummary<String>,
+    /// Chains over the last k child states, if the processor fits them
+    #[serde(default)]
+    pub(crate) history_markov: Option<HistoryMarkovSummary>,
+    pub(crate) fail_bernoulli: FailBernoulliSummary,
+    /// Failures of each parent span out of its failures and successes with
+    /// a given origin failing beneath it, if traced. Executions without the
+    /// origin are not counted, compare against `fail_bernoulli` totals.
+    #[serde(default)]
+    pub(crate) failure_origins: ManyCategoryBernSummary<String>,
+    /// Groups of overlapping children per parent span, see `crate::fanout`
+    #[serde(default)]
+    pub(crate) fan_out: ContextFanOutSummary,
+    /// Failures along `follows_from` edges by cause and effect, see `crate::causal`
+    #[serde(default)]
+    pub(crate) causal: ContextCausalSummary,
     // time_normal: ManyCategoryNormalSummary<String>,
+    #[serde(default)]
+    pub(crate) critical_path: ManyCategoryNormalSummary<String>,
+    /// Span names declared at more than one call site, whose statistics merge
+    /// unless states are qualified, see `crate::mapper::QualifiedMapper`
+    #[serde(default)]
+    pub(crate) ambiguous_names: BTreeMap<String, BTreeSet<Callsite>>,
+    /// Spans still open as of the latest reaper pass, see `crate::reaper`
+    #[serde(default)]
+    pub(crate) open_spans: OpenSpanStats,
+    /// Summaries of custom estimators by registered name
+    #[serde(default)]
+    pub(crate) custom: BTreeMap<String, serde_json::Value>,
 }
 
 impl DependencySummary {
+    /// Read every summary line written by `DependencyProcessor::write_summary`
+    pub fn read_all(path: &str) -> std::io::Result<Vec<DependencySummary>> {
+        BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| serde_json::from_str(&line?).map_err(std::io::Error::from))
+            .collect()
+    }
+
+    /// Read the most recent summary line
+    pub fn read_latest(path: &str) -> std::io::Result<DependencySummary> {
+        let mut latest = None;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                latest = Some(line);
+            }
+        }
+        match latest {
+            Some(line) => Ok(serde_json::from_str(&line)?),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("No dependency summary in {}", path),
+            )),
+        }
+    }
+
     pub fn make_span_markov(&self) -> ContextMarkovSummary<String> {
         // TODO: make model out of summary
         self.span_markov.clone()
     }
 
-    pub fn make_fail_bernoulli(&self) -> ManyCategoryBernSummary<String> {
+    pub fn make_state_counts(&self) -> ContextCountSummary<String> {
+        self.state_counts.clone()
+    }
+
+    pub fn make_history_markov(&self) -> Option<HistoryMarkovSummary> {
+        self.history_markov.clone()
+    }
+
+    /// Swap the first-order chains for the higher-order ones, if any, so
+    /// that analyses and exports read the unrolled history chains instead
+    pub fn unroll_history(&mut self) -> bool {
+        match self.history_markov.take() {
+            Some(history_markov) => {
+                self.span_markov = history_markov.chains;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn make_fail_bernoulli(&self) -> FailBernoulliSummary {
         // TODO: make model out of summary
         self.fail_bernoulli.clone()
     }
 
+    pub fn make_failure_origins(&self) -> ManyCategoryBernSummary<String> {
+        self.failure_origins.clone()
+    }
+
+    pub fn make_fan_out(&self) -> ContextFanOutSummary {
+        self.fan_out.clone()
+    }
+
+    pub fn make_causal(&self) -> ContextCausalSummary {
+        self.causal.clone()
+    }
+
+    pub fn make_critical_path(&self) -> ManyCategoryNormalSummary<String> {
+        self.critical_path.clone()
+    }
+
+    pub fn make_ambiguous_names(&self) -> BTreeMap<String, BTreeSet<Callsite>> {
+        self.ambiguous_names.clone()
+    }
+
+    pub fn make_open_spans(&self) -> OpenSpanStats {
+        self.open_spans.clone()
+    }
+
+    pub fn make_custom(&self, name: &str) -> Option<serde_json::Value> {
+        self.custom.get(name).cloned()
+    }
+
     // pub fn make_time_normal(&self) -> ManyCategoryNormalSummary<String> {
     //     // TODO: make model out of summary
     //     self.time_normal.clone()
@@ -48,8 +278,21 @@ impl DependencySummary {
 /* Process dependency data */
 pub struct DependencyProcessor {
     span_markov: ContextMarkovEstimator<String>,
-    fail_bernoulli: ManyCategoryBernEstimator<String>,
+    history_markov: ContextMarkovEstimator<String>,
+    markov_order: usize,
+    fail_bernoulli: ManyCategoryBernEstimator<String, SubspanSet>,
+    fail_total: CategoryBernEstimator<String>,
+    failure_origins: ManyCategoryBernEstimator<String>,
+    origins: FailureOrigins,
+    fan_out: Sharded<ContextFanOutEstimator>,
+    causal: Sharded<ContextCausalEstimator>,
     // time_normal: ManyCategoryNormalEstimator<String>,
+    critical_path: ManyCategoryNormalEstimator<String>,
+    mapper: Box<dyn StateMapper>,
+    custom: Vec<(String, Box<dyn CustomEstimator>)>,
+    callsites: RwLock<BTreeMap<&'static str, BTreeSet<Callsite>>>,
+    guard: CardinalityGuard,
+    open_spans: RwLock<OpenSpanStats>,
 }
 
 impl Default for DependencyProcessor {
@@ -62,71 +305,354 @@ impl DependencyProcessor {
     pub fn new() -> Self {
         DependencyProcessor {
             span_markov: ContextMarkovEstimator::default(),
+            history_markov: ContextMarkovEstimator::default(),
+            markov_order: 1,
             fail_bernoulli: ManyCategoryBernEstimator::default(),
+            fail_total: CategoryBernEstimator::default(),
+            failure_origins: ManyCategoryBernEstimator::default(),
+            origins: FailureOrigins::Off,
+            fan_out: Sharded::default(),
+            causal: Sharded::default(),
             // time_normal: ManyCategoryNormalEstimator::default(),
+            critical_path: ManyCategoryNormalEstimator::default(),
+            mapper: Box::new(NameMapper),
+            custom: Vec::new(),
+            callsites: RwLock::new(BTreeMap::new()),
+            guard: CardinalityGuard::new(CardinalityLimits::default()),
+            open_spans: RwLock::new(OpenSpanStats::default()),
         }
     }
 
-    pub fn record_span(&self, current_sr: &SpanRecord, parent_sr: &SpanRecord) {
-        log::trace!("Span {} [ {} ]", self.map_record(parent_sr), self.map_record(current_sr));
-        self.span_markov.observe(
-            self.map_record(parent_sr),
-            INITIAL_STATE.to_string(),
-            self.map_record(current_sr),
-        );
-        // self.time_normal.observe(
-        //     self.map_record(parent_sr),
-        //     self.map_record(current_sr),
-        //     parent_sr.elapsed().as_secs_f64(),
-        // );
+    /// Decide how span records become states, see `crate::mapper`
+    pub fn with_mapper<M: StateMapper + 'static>(mut self, mapper: M) -> Self {
+        self.mapper = Box::new(mapper);
+        self
     }
 
-    pub fn record_span_follows(&self, current_sr: &SpanRecord, prev_sr: &SpanRecord, parent_sr: &SpanRecord) {
-        log::trace!("Span {} [ {} --> {} ]", self.map_record(parent_sr), self.map_record(prev_sr), self.map_record(current_sr));
-        self.span_markov.observe(
-            self.map_record(parent_sr),
-            self.map_record(prev_sr),
-            self.map_record(current_sr),
-        );
-        // self.time_normal.observe(
-        //     self.map_record(parent_sr),
-        //     self.map_record(current_sr),
-        //     parent_sr.elapsed().as_secs_f64(),
-        // );
+    /// Also fit chains over the last `order` child states, which can tell
+    /// apart e.g. the fourth `call_c` in a row from the first one
+    pub fn with_markov_order(mut self, order: usize) -> Self {
+        self.markov_order = order.max(1);
+        self
+    }
+
+    /// Trace failures down to the descendants they came from, and count
+    /// parent failures per origin
+    pub fn with_failure_origins(mut self, origins: FailureOrigins) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// Feed an extra estimator from closing spans and events, summarized
+    /// under `name`, see `crate::custom`
+    pub fn with_estimator<N, C>(mut self, name: N, estimator: C) -> Self
+    where
+        N: Into<String>,
+        C: CustomEstimator + 'static,
+    {
+        self.custom.push((name.into(), Box::new(estimator)));
+        self
+    }
+
+    /// Cap the number of distinct keys per estimator map, see `crate::limits`
+    pub fn with_limits(mut self, limits: CardinalityLimits) -> Self {
+        self.guard = CardinalityGuard::new(limits);
+        self
+    }
+
+    /// Approximate size of the estimator maps
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.guard.memory_usage()
+    }
+
+    /// Break states down by these span fields in addition to the span name,
+    /// e.g. `call_rpc{peer=node3}` and `call_rpc{peer=node1}`
+    pub fn with_key_fields<I, F>(self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = F>,
+        F: Into<String>,
+    {
+        self.with_mapper(FieldsMapper::new(fields))
+    }
+
+    /// Remember where each span name is declared
+    pub fn record_callsite(&self, metadata: &'static Metadata<'static>) {
+        let mut callsites = self.callsites.write().unwrap();
+        let name_callsites = callsites.entry(metadata.name()).or_default();
+        name_callsites.insert(Callsite::from_metadata(metadata));
+        if name_callsites.len() == 2 {
+            log::warn!("Span name {} declared at multiple call sites {:?}", metadata.name(), name_callsites);
+        }
+    }
+
+    /// Step a parent's chain into a closed child, or a fan-out group of
+    /// children that ran alongside each other
+    pub fn record_subspan(&self, next: &SpanKey, prev: Option<&SpanKey>, parent_sr: &SpanRecord, last: &PendingChild) {
+        let state = match prev {
+            Some(prev) => prev.state.clone(),
+            None => INITIAL_STATE.to_string(),
+        };
+        log::trace!("Span {} [ {} --> {} ]", self.map_context(parent_sr), state, next.state);
+        // sampled as the last child settling it, root children come from many traces
+        self.observe_sampled(last.trace.as_ref(), last.weight, Observation::Transition {
+            context: self.map_context(parent_sr),
+            state,
+            next_state: next.state.clone(),
+        });
+        self.observe_history_sampled(last.trace.as_ref(), last.weight, parent_sr, next.state.clone());
+    }
+
+    /// Report the fan-out groups of a closing span along with how it ended
+    pub fn record_fan_outs(&self, parent_sr: &SpanRecord) {
+        for group in parent_sr.fan_outs.iter() {
+            self.observe(parent_sr, Observation::FanOut {
+                context: self.map_context(parent_sr),
+                fan_out: FanOutObservation {
+                    group: group.clone(),
+                    parent_failed: parent_sr.failing,
+                },
+            });
+        }
+    }
+
+    /// Report a fan-out group right away, for parents that never close
+    pub fn record_fan_out(&self, group: &FanOutGroup, parent_sr: &SpanRecord, last: &PendingChild) {
+        self.observe_sampled(last.trace.as_ref(), last.weight, Observation::FanOut {
+            context: self.map_context(parent_sr),
+            fan_out: FanOutObservation {
+                group: group.clone(),
+                parent_failed: false,
+            },
+        });
+    }
+
+    /// Hand a failing child's origins, or the child itself, up to its parent
+    pub fn propagate_failure_origins(&self, current_sr: &SpanRecord, parent_sr: &mut SpanRecord) {
+        if self.origins == FailureOrigins::Off || !current_sr.failing {
+            return;
+        }
+        let state = self.map_state(current_sr);
+        if current_sr.failing_origins.is_empty() {
+            parent_sr.failing_origins.insert(state);
+            return;
+        }
+        for origin in current_sr.failing_origins.iter() {
+            match self.origins {
+                FailureOrigins::Path => parent_sr.failing_origins.insert(format!("{}{}{}", state, ORIGIN_SEPARATOR, origin)),
+                _ => parent_sr.failing_origins.insert(origin.clone()),
+            };
+        }
+    }
+
+    /// Report every span a closing span follows from, and whether it had failed
+    pub fn record_causes(&self, current_sr: &SpanRecord) {
+        for cause in current_sr.causes.iter() {
+            let cause_failed = *cause.outcome.lock().unwrap();
+            log::trace!("Follow {} ~~> {}", cause.state, self.map_state(current_sr));
+            self.observe(current_sr, Observation::Causal {
+                cause: cause.state.clone(),
+                effect: self.map_state(current_sr),
+                causal: CausalObservation {
+                    cause_failed,
+                    effect_failed: current_sr.failing,
+                },
+            });
+        }
     }
 
     pub fn record_span_succeeds(&self, parent_sr: &SpanRecord) {
         self.record_span_succeeds_inner(INITIAL_STATE.to_string(), parent_sr);
     }
 
-    pub fn record_span_succeeds_from(&self, current_sr: &SpanRecord, parent_sr: &SpanRecord) {
-        self.record_span_succeeds_inner(self.map_record(current_sr), parent_sr);
+    pub fn record_span_succeeds_from(&self, prev: &SpanKey, parent_sr: &SpanRecord) {
+        self.record_span_succeeds_inner(prev.state.clone(), parent_sr);
     }
 
     pub fn record_span_fails(&self, parent_sr: &SpanRecord) {
         self.record_span_fails_inner(INITIAL_STATE.to_string(), parent_sr);
     }
 
-    pub fn record_span_fails_from(&self, current_sr: &SpanRecord, parent_sr: &SpanRecord) {
-        self.record_span_fails_inner(self.map_record(current_sr), parent_sr);
+    pub fn record_span_fails_from(&self, prev: &SpanKey, parent_sr: &SpanRecord) {
+        self.record_span_fails_inner(prev.state.clone(), parent_sr);
+    }
+
+    /// End a span that never closed in `__ABANDONED__`, without counting
+    /// it as a success or a failure
+    pub fn record_span_abandons(&self, parent_sr: &SpanRecord) {
+        log::trace!("Abandon {}", self.map_context(parent_sr));
+        let current = match &parent_sr.latest {
+            Some(latest) => latest.state.clone(),
+            None => INITIAL_STATE.to_string(),
+        };
+        self.observe(parent_sr, Observation::Transition {
+            context: self.map_context(parent_sr),
+            state: current,
+            next_state: ABANDONED_STATE.to_string(),
+        });
+        self.observe_history(parent_sr, parent_sr, ABANDONED_STATE.to_string());
+    }
+
+    /// Append a closed child's state to its parent's history, if fitting
+    /// higher-order chains
+    pub fn push_history(&self, parent_sr: &mut SpanRecord, state: &str) {
+        if self.markov_order == 1 {
+            return;
+        }
+        if parent_sr.history.is_empty() {
+            parent_sr.history.push_back(INITIAL_STATE.to_string());
+        }
+        parent_sr.history.push_back(state.to_string());
+        while parent_sr.history.len() > self.markov_order {
+            parent_sr.history.pop_front();
+        }
+    }
+
+    /// Identity of a closing span as the latest child of its parent
+    pub fn span_key(&self, sr: &SpanRecord) -> SpanKey {
+        SpanKey {
+            id: sr.id.clone(),
+            state: self.map_state(sr),
+        }
+    }
+
+    /// Feed a closing span to custom estimators
+    pub fn record_custom_span(&self, current_sr: &SpanRecord, parent_sr: &SpanRecord) {
+        if self.custom.is_empty() {
+            return;
+        }
+        self.observe(current_sr, Observation::Custom(CustomObservation {
+            source: ObservationSource::Span,
+            parent: self.map_context(parent_sr),
+            state: self.map_state(current_sr),
+            fields: current_sr.fields.clone(),
+        }));
+    }
+
+    /// Feed an event inside `current_sr`, whose parent maps to `parent`, to custom estimators
+    pub fn record_custom_event(
+        &self,
+        current_sr: &SpanRecord,
+        parent: String,
+        fields: BTreeMap<&'static str, String>,
+    ) {
+        self.observe(current_sr, Observation::Custom(CustomObservation {
+            source: ObservationSource::Event,
+            parent,
+            state: self.map_state(current_sr),
+            fields,
+        }));
+    }
+
+    pub fn has_custom_estimators(&self) -> bool {
+        !self.custom.is_empty()
+    }
+
+    /// Whether span fields go into states or custom estimators
+    pub fn reads_fields(&self) -> bool {
+        self.mapper.reads_fields() || self.has_custom_estimators()
+    }
+
+    /// Latest statistics of spans still open, reported by the layer's reaper
+    pub fn record_open_spans(&self, stats: OpenSpanStats) {
+        *self.open_spans.write().unwrap() = stats;
+    }
+
+    /// Attribute a closing span's wall time to its children on the critical
+    /// path and to its own self/idle time, alongside its busy and idle time
+    pub fn record_critical_path(&self, sr: &SpanRecord) {
+        let end = Instant::now();
+        let (on_path, self_time) = sr.critical_path(end);
+        let parent = self.map_context(sr);
+        let wall_time = end.saturating_duration_since(sr.creation_time);
+        let busy_time = sr.busy().min(wall_time);
+        self.observe(sr, Observation::Time {
+            context: parent.clone(),
+            subrecord: TOTAL_SUBRECORD.to_string(),
+            seconds: wall_time.as_secs_f64(),
+        });
+        self.observe(sr, Observation::Time {
+            context: parent.clone(),
+            subrecord: BUSY_SUBRECORD.to_string(),
+            seconds: busy_time.as_secs_f64(),
+        });
+        self.observe(sr, Observation::Time {
+            context: parent.clone(),
+            subrecord: IDLE_SUBRECORD.to_string(),
+            seconds: (wall_time - busy_time).as_secs_f64(),
+        });
+        self.observe(sr, Observation::Time {
+            context: parent.clone(),
+            subrecord: SELF_SUBRECORD.to_string(),
+            seconds: self_time.as_secs_f64(),
+        });
+        for (child, duration) in on_path {
+            self.observe(sr, Observation::Time {
+                context: parent.clone(),
+                subrecord: child.to_string(),
+                seconds: duration.as_secs_f64(),
+            });
+        }
     }
 
     pub fn summarize(&self) -> DependencySummary {
+        let span_totals = self.span_markov.summarize_totals();
         DependencySummary {
-            span_markov: self.span_markov.clone().summarize(),
-            fail_bernoulli: self.fail_bernoulli.clone().summarize(),
+            span_markov: span_totals.chains,
+            state_counts: span_totals.counts,
+            state_samples: span_totals.samples,
+            history_markov: (self.markov_order > 1).then(|| HistoryMarkovSummary {
+                order: self.markov_order,
+                chains: self.history_markov.summarize(),
+            }),
+            fail_bernoulli: self.summarize_fail_bernoulli(),
+            failure_origins: self.failure_origins.summarize(),
+            fan_out: self.fan_out.summarize(),
+            causal: self.causal.summarize(),
             // time_normal: self.time_normal.clone().summarize(),
+            critical_path: self.critical_path.summarize(),
+            ambiguous_names: self.summarize_ambiguous_names(),
+            open_spans: self.open_spans.read().unwrap().clone(),
+            custom: self.custom.iter()
+                .map(|(name, estimator)| (name.clone(), estimator.summarize()))
+                .collect(),
         }
     }
 
+    fn summarize_fail_bernoulli(&self) -> FailBernoulliSummary {
+        let mut failing_subspans = self.fail_bernoulli.summarize();
+        let mut samples = self.fail_total.summarize_samples();
+        self.fail_total.summarize()
+            .into_iter()
+            .map(|(context, total)| {
+                let fail_summary = FailSummary {
+                    total,
+                    samples: samples.remove(&context).unwrap_or_default(),
+                    failing_subspans: failing_subspans.remove(&context)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .collect(),
+                };
+                (context, fail_summary)
+            })
+            .collect()
+    }
+
+    fn summarize_ambiguous_names(&self) -> BTreeMap<String, BTreeSet<Callsite>> {
+        self.callsites.read().unwrap()
+            .iter()
+            .filter(|(_, callsites)| callsites.len() > 1)
+            .map(|(name, callsites)| (name.to_string(), callsites.clone()))
+            .collect()
+    }
+
     pub fn write_summary(&self) -> std::io::Result<()> {
         let summary = self.summarize();
         log::debug!("Current dependency summary {:#?}", summary);
+        log::debug!("Current estimator memory usage {:?}", self.memory_usage());
 
         if let Ok(summary_json) = serde_json::to_string(&summary) {
             if let Ok(mut log_file) = OpenOptions::new()
                 .create(true)
-                .write(true)
                 .append(true)
                 .open(DEPENDENCY_OUT) {
                 log_file.write_all(summary_json.as_bytes())?;
@@ -167,62 +693,223 @@ impl DependencyProcessor {
     }
 
     fn record_span_succeeds_inner(&self, current: String, parent_sr: &SpanRecord) {
-        log::trace!("Succeed {}", self.map_record(parent_sr));
-        self.span_markov.observe(
-            self.map_record(parent_sr),
-            current,
-            SUCCESS_STATE.to_string(),
-        );
-        self.fail_bernoulli.observe_absent(
-            self.map_record(parent_sr),
-            self.map_subrecord(parent_sr),
-        );
-        self.fail_bernoulli.observe_absent(
-            self.map_record(parent_sr),
-            TOTAL_SUBRECORD.to_string(),
-        );
+        log::trace!("Succeed {}", self.map_context(parent_sr));
+        self.observe(parent_sr, Observation::Transition {
+            context: self.map_context(parent_sr),
+            state: current,
+            next_state: SUCCESS_STATE.to_string(),
+        });
+        self.observe_history(parent_sr, parent_sr, SUCCESS_STATE.to_string());
+        self.observe(parent_sr, Observation::Fail {
+            context: self.map_context(parent_sr),
+            failing_subspans: parent_sr.failing_subspans.clone(),
+            failed: false,
+        });
+        self.observe_failure_origins(parent_sr, false);
         // self.time_normal.observe(
-        //     self.map_record(parent_sr),
+        //     self.map_context(parent_sr),
         //     SUCCESS_STATE.to_string(),
         //     parent_sr.elapsed().as_secs_f64(),
         // );
     }
 
     fn record_span_fails_inner(&self, current: String, parent_sr: &SpanRecord) {
-        log::trace!("Fail {}", self.map_record(parent_sr));
-        self.span_markov.observe(
-            self.map_record(parent_sr),
-            current,
-            FAILURE_STATE.to_string(),
-        );
-        self.fail_bernoulli.observe_present(
-            self.map_record(parent_sr),
-            self.map_subrecord(parent_sr),
-        );
-        self.fail_bernoulli.observe_present(
-            self.map_record(parent_sr),
-            TOTAL_SUBRECORD.to_string(),
-        );
+        log::trace!("Fail {}", self.map_context(parent_sr));
+        self.observe(parent_sr, Observation::Transition {
+            context: self.map_context(parent_sr),
+            state: current,
+            next_state: FAILURE_STATE.to_string(),
+        });
+        self.observe_history(parent_sr, parent_sr, FAILURE_STATE.to_string());
+        self.observe(parent_sr, Observation::Fail {
+            context: self.map_context(parent_sr),
+            failing_subspans: parent_sr.failing_subspans.clone(),
+            failed: true,
+        });
+        self.observe_failure_origins(parent_sr, true);
         // self.time_normal.observe(
-        //     self.map_record(parent_sr),
+        //     self.map_context(parent_sr),
         //     FAILURE_STATE.to_string(),
         //     parent_sr.elapsed().as_secs_f64(),
         // );
     }
 
-    fn map_record(&self, sr: &SpanRecord) -> String {
-        // if sr.failing {
-        //     format!("{} [FAIL]", sr.name)
-        // } else {
-        //     sr.name.to_string()
-        // }
-        sr.name.to_string()
+    /// Take the tail draw of a trace, feeding its buffered observations into
+    /// the estimators if kept
+    pub fn flush_trace(&self, trace: &TraceHandle) {
+        let mut buffer = trace.lock().unwrap();
+        if let TailDecision::Keep(weight) = buffer.decide() {
+            let observations = std::mem::take(&mut buffer.observations);
+            drop(buffer);
+            for observation in observations {
+                self.apply(observation, weight);
+            }
+        }
     }
 
-    fn map_subrecord(&self, sr: &SpanRecord) -> String {
-        sr.failing_subspans
-            .iter()
-            // .sorted()  // no need since BTreeSet
-            .join(", ")
+    fn observe_failure_origins(&self, parent_sr: &SpanRecord, failed: bool) {
+        if parent_sr.failing_origins.is_empty() {
+            return;
+        }
+        self.observe(parent_sr, Observation::Origins {
+            context: self.map_context(parent_sr),
+            origins: parent_sr.failing_origins.clone(),
+            failed,
+        });
     }
-}
\ No newline at end of file
+
+    /* Transition of the parent's history chain, into a bare terminal state */
+    fn observe_history(&self, current_sr: &SpanRecord, parent_sr: &SpanRecord, next_state: String) {
+        self.observe_history_sampled(current_sr.trace.as_ref(), current_sr.weight, parent_sr, next_state);
+    }
+
+    fn observe_history_sampled(&self, trace: Option<&TraceHandle>, weight: f64, parent_sr: &SpanRecord, next_state: String) {
+        if self.markov_order == 1 {
+            return;
+        }
+        let mut states: Vec<&str> = parent_sr.history.iter().map(String::as_str).collect();
+        if states.is_empty() {
+            states.push(INITIAL_STATE);
+        }
+        let history = history_state(states.iter().copied());
+        let next_history = if next_state == SUCCESS_STATE || next_state == FAILURE_STATE || next_state == ABANDONED_STATE {
+            next_state
+        } else {
+            states.push(&next_state);
+            history_state(states[states.len().saturating_sub(self.markov_order)..].iter().copied())
+        };
+        self.observe_sampled(trace, weight, Observation::History {
+            context: self.map_context(parent_sr),
+            history,
+            next_history,
+        });
+    }
+
+    /* Hold the observation back if the span's trace awaits a tail decision */
+    fn observe(&self, sr: &SpanRecord, observation: Observation) {
+        self.observe_sampled(sr.trace.as_ref(), sr.weight, observation);
+    }
+
+    fn observe_sampled(&self, trace: Option<&TraceHandle>, weight: f64, observation: Observation) {
+        let trace = match trace {
+            Some(trace) => trace,
+            None => return self.apply(observation, weight),
+        };
+        let mut buffer = trace.lock().unwrap();
+        match buffer.decision() {
+            TailDecision::Pending => {
+                buffer.observations.push(observation);
+                if buffer.is_full() {
+                    drop(buffer);
+                    self.flush_trace(trace);
+                }
+            },
+            TailDecision::Keep(weight) => {
+                drop(buffer);
+                self.apply(observation, weight);
+            },
+            TailDecision::Drop => {},
+        }
+    }
+
+    fn apply(&self, observation: Observation, weight: f64) {
+        match observation {
+            Observation::Transition { context, state, next_state } => {
+                let context = self.guard.admit_context(context);
+                let state = self.guard.admit_state(&context, state);
+                let next_state = self.guard.admit_state(&context, next_state);
+                self.span_markov.observe_weighted(context, state, next_state, weight);
+            }
+            Observation::History { context, history, next_history } => {
+                let context = self.guard.admit_context(context);
+                let history = self.guard.admit_history(&context, history);
+                let next_history = self.guard.admit_history(&context, next_history);
+                self.history_markov.observe_weighted(context, history, next_history, weight);
+            }
+            Observation::Fail { context, failing_subspans, failed } => {
+                let context = self.guard.admit_context(context);
+                let failing_subspans = self.guard.admit_failing_set(&context, failing_subspans);
+                if failed {
+                    self.fail_bernoulli.observe_present_weighted(context.clone(), failing_subspans, weight);
+                    self.fail_total.observe_present_weighted(context, weight);
+                } else {
+                    self.fail_bernoulli.observe_absent_weighted(context.clone(), failing_subspans, weight);
+                    self.fail_total.observe_absent_weighted(context, weight);
+                }
+            }
+            Observation::FanOut { context, mut fan_out } => {
+                let context = self.guard.admit_context(context);
+                let group = self.guard.admit_state(&context, std::mem::take(&mut fan_out.group.state));
+                self.fan_out.observe((context, (group, fan_out)), weight);
+            }
+            Observation::Causal { cause, effect, causal } => {
+                let cause = self.guard.admit_state(CAUSES_CONTEXT, cause);
+                let effect = self.guard.admit_state(&cause, effect);
+                self.causal.observe((cause, (effect, causal)), weight);
+            }
+            Observation::Origins { context, origins, failed } => {
+                let context = self.guard.admit_context(context);
+                for origin in origins {
+                    let origin = self.guard.admit_origin(&context, origin);
+                    if failed {
+                        self.failure_origins.observe_present_weighted(context.clone(), origin, weight);
+                    } else {
+                        self.failure_origins.observe_absent_weighted(context.clone(), origin, weight);
+                    }
+                }
+            }
+            Observation::Time { context, subrecord, seconds } => {
+                let context = self.guard.admit_context(context);
+                let subrecord = self.guard.admit_state(&context, subrecord);
+                self.critical_path.observe_weighted(context, subrecord, seconds, weight);
+            }
+            Observation::Custom(mut observation) => {
+                observation.parent = self.guard.admit_context(observation.parent);
+                observation.state = self.guard.admit_state(&observation.parent, observation.state);
+                for (_, estimator) in self.custom.iter() {
+                    estimator.observe(&observation, weight);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn map_state(&self, sr: &SpanRecord) -> String {
+        self.mapper.map_state(sr)
+    }
+
+    pub(crate) fn map_context(&self, sr: &SpanRecord) -> String {
+        self.mapper.map_context(sr)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fail_summary_reads_joined_subspans() {
+        let fail_summary: FailSummary = serde_json::from_str(
+            r#"{"":[0,47],"__TOTAL__":[43,100],"call_c, call_e":[4,4]}"#,
+        ).unwrap();
+        assert_eq!(fail_summary.total, (43, 100));
+        assert_eq!(fail_summary.samples, (43, 100));
+        let failing_subspans: BTreeMap<SubspanSet, BernSummary> = fail_summary.failing_subspans.into_iter().collect();
+        assert_eq!(failing_subspans.get(&SubspanSet::new()), Some(&(0, 47)));
+        let both: SubspanSet = ["call_c".to_string(), "call_e".to_string()].into();
+        assert_eq!(failing_subspans.get(&both), Some(&(4, 4)));
+    }
+
+    #[test]
+    fn fail_summary_round_trips() {
+        let fail_summary = FailSummary {
+            total: (2, 10),
+            failing_subspans: vec![(["call_b".to_string()].into(), (2, 3))],
+            samples: (2, 4),
+        };
+        let read: FailSummary = serde_json::from_str(&serde_json::to_string(&fail_summary).unwrap()).unwrap();
+        assert_eq!(read.total, (2, 10));
+        assert_eq!(read.failing_subspans, fail_summary.failing_subspans);
+        assert_eq!(read.samples, (2, 4));
+    }
+}
@@ -0,0 +1,65 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+
+/// How `DependencyLayer` treats spans that stay open for too long
+///
+/// Spans leaked by `mem::forget` or held by long-lived background tasks never
+/// reach `on_close`. Every `period`, checked whenever a new span is created,
+/// spans older than `max_age` are reported, or finalized into
+/// `__ABANDONED__` if `abandon` is set.
+#[derive(Clone, Copy, Debug)]
+pub struct Reaper {
+    pub max_age: Duration,
+    pub period: Duration,
+    pub abandon: bool,
+}
+
+impl Default for Reaper {
+    fn default() -> Self {
+        Reaper {
+            max_age: Duration::from_secs(600),
+            period: Duration::from_secs(10),
+            abandon: false,
+        }
+    }
+}
+
+impl Reaper {
+    /// Report spans open for longer than `max_age`
+    pub fn new(max_age: Duration) -> Self {
+        Reaper {
+            max_age,
+            ..Reaper::default()
+        }
+    }
+
+    pub fn with_period(mut self, period: Duration) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Also end them in `__ABANDONED__` and stop tracking them
+    pub fn abandoning(mut self) -> Self {
+        self.abandon = true;
+        self
+    }
+}
+
+
+/// Spans still open as of the latest reaper pass
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct OpenSpanStats {
+    pub open: usize,
+    /// Open for longer than the reaper's `max_age`
+    pub stale: usize,
+    /// Age of the oldest open span in seconds
+    pub oldest_age: f64,
+    pub mean_age: f64,
+    /// Spans finalized into `__ABANDONED__` so far
+    pub abandoned: usize,
+    /// Spans closed after their parent was abandoned, ending their own chain only
+    #[serde(default)]
+    pub orphaned: usize,
+}
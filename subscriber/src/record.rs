@@ -1,34 +1,399 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::time::Duration;
 use std::time::Instant;
+use tracing::field::Field;
+use tracing::field::Visit;
 use tracing::Id;
+use tracing::Metadata;
+
+use crate::causal::Cause;
+use crate::causal::OutcomeHandle;
+use crate::fanout::FanOutGroup;
+use crate::sampling::TraceHandle;
 
 
 #[derive(Clone, Debug)]
 pub struct SpanRecord {
     pub id: Id,
     pub name: &'static str,
+    pub target: &'static str,
+    pub module_path: Option<&'static str>,
+    pub file: Option<&'static str>,
+    pub line: Option<u32>,
+    pub parent: Option<Id>,
+    pub fields: BTreeMap<&'static str, String>,
     pub creation_time: Instant,
-    pub latest: Option<Box<SpanRecord>>,
+    /// Previous closed child, continuing this span's chain
+    pub latest: Option<SpanKey>,
+    /// Latest closed children's states, oldest first, kept for higher-order chains
+    pub history: VecDeque<String>,
+    /// Position among the parent's children, in creation order
+    pub sibling_seq: u64,
+    /// Children created so far
+    pub children_created: u64,
+    /// Children created and not closed yet, by position
+    pub open_children: BTreeSet<u64>,
+    /// Closed children waiting for earlier siblings to close, by position
+    pub closed_children: BTreeMap<u64, PendingChild>,
+    /// Closed children whose activity overlaps, waiting for more siblings
+    pub pending_children: Vec<PendingChild>,
+    /// Earliest start and latest end of the pending children's activity
+    pub pending_window: Option<(Instant, Instant)>,
+    /// Fan-out groups so far, reported once this span closes
+    pub fan_outs: Vec<FanOutGroup>,
+    /// Spans this one follows from, possibly in other traces
+    pub causes: Vec<Cause>,
+    /// Shared with spans following from this one, set once it closes
+    pub outcome: Option<OutcomeHandle>,
     pub failing: bool,
     pub failing_subspans: BTreeSet<String>,
+    /// Deepest failing descendants, or paths down to them, if traced
+    pub failing_origins: BTreeSet<String>,
+    /// Closed children's lifetimes not folded yet, see `SpanRecord::push_subspan`
+    pub closed_subspans: Vec<SubspanInterval>,
+    /// Critical path of the children folded away so far
+    pub folded_path: Option<FoldedPath>,
+    pub busy_time: Duration,
+    pub entered_depth: usize,
+    pub entered_time: Option<Instant>,
+    pub first_entered: Option<Instant>,
+    pub last_exited: Option<Instant>,
+    /// Number of traces this span stands for under sampling
+    pub weight: f64,
+    /// Buffer holding this trace's observations until the tail decision
+    pub trace: Option<TraceHandle>,
+}
+
+/// Where a span is declared in the source
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Callsite {
+    pub target: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl Callsite {
+    pub fn from_metadata(metadata: &Metadata<'_>) -> Callsite {
+        Callsite {
+            target: metadata.target().to_string(),
+            module_path: metadata.module_path().map(str::to_string),
+            file: metadata.file().map(str::to_string),
+            line: metadata.line(),
+        }
+    }
+}
+
+/// Identity of a closed span, enough to continue its parent's chain
+#[derive(Clone, Debug)]
+pub struct SpanKey {
+    pub id: Id,
+    /// State the span was mapped to when it closed
+    pub state: String,
+}
+
+/// Closed child span whose siblings may still be running alongside it
+#[derive(Clone, Debug)]
+pub struct PendingChild {
+    pub key: SpanKey,
+    pub failing: bool,
+    /// Sampling of the child's trace, which settles the parent's chain
+    pub trace: Option<TraceHandle>,
+    pub weight: f64,
+    /// First entry and last exit of the child, see `SpanRecord::active_interval`
+    pub start: Instant,
+    pub end: Instant,
+}
+
+/// Lifetime of a closed child span
+#[derive(Clone, Debug)]
+pub struct SubspanInterval {
+    pub key: String,
+    pub start: Instant,
+    pub end: Instant,
+}
+
+/// Critical path of a long-lived span's older children, up to `until`
+#[derive(Clone, Debug)]
+pub struct FoldedPath {
+    pub until: Instant,
+    pub on_path: BTreeMap<String, Duration>,
+    pub self_time: Duration,
 }
 
+/* Closed children kept per span before older ones are folded */
+const MAX_SUBSPAN_INTERVALS: usize = 1024;
+
 impl SpanRecord {
     pub fn new(id: Id, name: &'static str) -> SpanRecord {
         SpanRecord {
           id,
           name,
+          target: "",
+          module_path: None,
+          file: None,
+          line: None,
+          parent: None,
+          fields: BTreeMap::new(),
           creation_time: Instant::now(),
           latest: None,
+          history: VecDeque::new(),
+          sibling_seq: 0,
+          children_created: 0,
+          open_children: BTreeSet::new(),
+          closed_children: BTreeMap::new(),
+          pending_children: Vec::new(),
+          pending_window: None,
+          fan_outs: Vec::new(),
+          causes: Vec::new(),
+          outcome: None,
           failing: false,
           failing_subspans: BTreeSet::new(),
+          failing_origins: BTreeSet::new(),
+          closed_subspans: Vec::new(),
+          folded_path: None,
+          busy_time: Duration::ZERO,
+          entered_depth: 0,
+          entered_time: None,
+          first_entered: None,
+          last_exited: None,
+          weight: 1.0,
+          trace: None,
         }
     }
 
+    /// Fill in the span's declaration site
+    pub fn set_metadata(&mut self, metadata: &'static Metadata<'static>) {
+        self.target = metadata.target();
+        self.module_path = metadata.module_path();
+        self.file = metadata.file();
+        self.line = metadata.line();
+    }
+
     pub fn elapsed(&self) -> Duration {
         self.creation_time.elapsed()
     }
+
+    pub fn enter(&mut self) {
+        if self.entered_depth == 0 {
+            let now = Instant::now();
+            self.entered_time = Some(now);
+            self.first_entered.get_or_insert(now);
+        }
+        self.entered_depth += 1;
+    }
+
+    pub fn exit(&mut self) {
+        self.entered_depth = self.entered_depth.saturating_sub(1);
+        if self.entered_depth == 0 {
+            if let Some(entered_time) = self.entered_time.take() {
+                self.busy_time += entered_time.elapsed();
+                self.last_exited = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Take the position of a new child
+    pub fn open_child(&mut self) -> u64 {
+        let seq = self.children_created;
+        self.children_created += 1;
+        self.open_children.insert(seq);
+        seq
+    }
+
+    /// From the first entry to the last exit, or up to `now` if still
+    /// entered, and only `now` if never entered
+    pub fn active_interval(&self, now: Instant) -> (Instant, Instant) {
+        match (self.first_entered, self.entered_time) {
+            (Some(first), None) => (first, self.last_exited.unwrap_or(now)),
+            (Some(first), Some(_)) => (first, now),
+            (None, _) => (now, now),
+        }
+    }
+
+    /// Time spent entered, including a still-ongoing entry
+    pub fn busy(&self) -> Duration {
+        match self.entered_time {
+            Some(entered_time) => self.busy_time + entered_time.elapsed(),
+            None => self.busy_time,
+        }
+    }
+
+    /// Time alive but not entered, e.g. parked on an async executor
+    pub fn idle(&self) -> Duration {
+        self.elapsed().saturating_sub(self.busy())
+    }
+
+    /// Keep a closed child's lifetime for the critical path
+    ///
+    /// Past `MAX_SUBSPAN_INTERVALS`, the older half of the children is folded
+    /// into `folded_path`, walking their critical path once. Children still
+    /// kept that started before the fold are cut at it.
+    pub fn push_subspan(&mut self, interval: SubspanInterval) {
+        self.closed_subspans.push(interval);
+        if self.closed_subspans.len() <= MAX_SUBSPAN_INTERVALS {
+            return;
+        }
+        self.closed_subspans.sort_by_key(|interval| interval.end);
+        let kept = self.closed_subspans.split_off(self.closed_subspans.len() / 2);
+        let folded = std::mem::replace(&mut self.closed_subspans, kept);
+        let until = folded.last().map(|interval| interval.end).unwrap_or(self.creation_time);
+        for interval in self.closed_subspans.iter_mut() {
+            interval.start = interval.start.max(until);
+        }
+
+        let floor = self.folded_path.as_ref().map(|fold| fold.until).unwrap_or(self.creation_time);
+        let (on_path, self_time) = critical_walk(&folded, floor, until);
+        let fold = self.folded_path.get_or_insert_with(|| FoldedPath {
+            until,
+            on_path: BTreeMap::new(),
+            self_time: Duration::ZERO,
+        });
+        fold.until = until;
+        fold.self_time += self_time;
+        for (key, duration) in on_path {
+            *fold.on_path.entry(key).or_default() += duration;
+        }
+    }
+
+    /// Split the time until `end` into each child's share of the critical
+    /// path and the remaining self time not covered by any child
+    ///
+    /// Walks back from `end`, each time following the child that finished
+    /// last before the cursor. Children that ran entirely off the critical
+    /// path are still reported with a zero share.
+    pub fn critical_path(&self, end: Instant) -> (BTreeMap<String, Duration>, Duration) {
+        let floor = match &self.folded_path {
+            Some(fold) => fold.until,
+            None => self.creation_time,
+        };
+        let (mut on_path, mut self_time) = critical_walk(&self.closed_subspans, floor, end);
+        if let Some(fold) = &self.folded_path {
+            self_time += fold.self_time;
+            for (key, duration) in fold.on_path.iter() {
+                *on_path.entry(key.clone()).or_default() += *duration;
+            }
+        }
+        (on_path, self_time)
+    }
+}
+
+/*
+ * Critical path between `floor` and `end`, in O(n log n)
+ *
+ *  Going through children by decreasing end, those ending at or after the
+ *  cursor cover it, and the one of them starting first is followed. If none
+ *  covers the cursor, the next child by end is the one that finished last
+ *  before it, and the gap counts as self time.
+ */
+fn critical_walk(intervals: &[SubspanInterval], floor: Instant, end: Instant) -> (BTreeMap<String, Duration>, Duration) {
+    let mut on_path: BTreeMap<String, Duration> = intervals.iter()
+        .map(|interval| (interval.key.clone(), Duration::ZERO))
+        .collect();
+    let mut by_end: Vec<&SubspanInterval> = intervals.iter().collect();
+    by_end.sort_by_key(|interval| std::cmp::Reverse(interval.end));
+
+    let mut self_time = Duration::ZERO;
+    let mut cursor = end;
+    let mut idx = 0;
+    let mut first_covering: Option<&SubspanInterval> = None;
+    while cursor > floor {
+        while idx < by_end.len() && by_end[idx].end >= cursor {
+            if first_covering.is_none_or(|covering| by_end[idx].start < covering.start) {
+                first_covering = Some(by_end[idx]);
+            }
+            idx += 1;
+        }
+        let (interval, child_end) = match first_covering.filter(|covering| covering.start < cursor) {
+            Some(covering) => (covering, cursor),
+            None if idx < by_end.len() && by_end[idx].end > floor => (by_end[idx], by_end[idx].end),
+            None => {
+                self_time += cursor.saturating_duration_since(floor);
+                break;
+            },
+        };
+        let child_start = interval.start.max(floor);
+        self_time += cursor.saturating_duration_since(child_end);
+        *on_path.get_mut(&interval.key).unwrap() += child_end.saturating_duration_since(child_start);
+        cursor = child_start;
+    }
+    (on_path, self_time)
+}
+
+/// Collects span fields into a `SpanRecord`
+pub struct FieldVisitor<'a>(pub &'a mut BTreeMap<&'static str, String>);
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
 }
 
 pub const ROOT_SPAN: &str = "__ROOT_SPAN__";
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn interval(sr: &SpanRecord, key: &str, start: u64, end: u64) -> SubspanInterval {
+        SubspanInterval {
+            key: key.to_string(),
+            start: sr.creation_time + ms(start),
+            end: sr.creation_time + ms(end),
+        }
+    }
+
+    #[test]
+    fn critical_path_follows_the_last_finishing_child() {
+        let mut sr = SpanRecord::new(Id::from_u64(1), "call_a");
+        // call_b [0, 30] runs alongside call_c [10, 50], then call_d [60, 90]
+        sr.push_subspan(interval(&sr, "call_b", 0, 30));
+        sr.push_subspan(interval(&sr, "call_c", 10, 50));
+        sr.push_subspan(interval(&sr, "call_d", 60, 90));
+        let (on_path, self_time) = sr.critical_path(sr.creation_time + ms(100));
+        assert_eq!(on_path["call_d"], ms(30));
+        assert_eq!(on_path["call_c"], ms(40));
+        assert_eq!(on_path["call_b"], ms(10));
+        assert_eq!(self_time, ms(20));
+    }
+
+    #[test]
+    fn critical_path_reports_children_off_the_path() {
+        let mut sr = SpanRecord::new(Id::from_u64(1), "call_a");
+        sr.push_subspan(interval(&sr, "call_b", 0, 100));
+        sr.push_subspan(interval(&sr, "call_c", 20, 40));
+        let (on_path, self_time) = sr.critical_path(sr.creation_time + ms(100));
+        assert_eq!(on_path["call_b"], ms(100));
+        assert_eq!(on_path["call_c"], Duration::ZERO);
+        assert_eq!(self_time, Duration::ZERO);
+    }
+
+    #[test]
+    fn folding_keeps_sequential_critical_paths() {
+        let mut sr = SpanRecord::new(Id::from_u64(1), "server_loop");
+        let children = 3 * MAX_SUBSPAN_INTERVALS as u64;
+        for idx in 0 .. children {
+            let key = if idx % 2 == 0 { "call_b" } else { "call_c" };
+            sr.push_subspan(interval(&sr, key, 10 * idx + 2, 10 * idx + 9));
+        }
+        assert!(sr.closed_subspans.len() <= MAX_SUBSPAN_INTERVALS);
+        assert!(sr.folded_path.is_some());
+        let (on_path, self_time) = sr.critical_path(sr.creation_time + ms(10 * children));
+        assert_eq!(on_path["call_b"] + on_path["call_c"], ms(7 * children));
+        assert_eq!(on_path["call_b"], on_path["call_c"]);
+        assert_eq!(self_time, ms(3 * children));
+    }
+}
@@ -0,0 +1,125 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::bernoulli::BernSummary;
+use crate::processor::DependencySummary;
+use crate::processor::SubspanSet;
+
+
+/// What a root-cause candidate is made of
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CauseKind {
+    /// A child span failing, alone or along with others
+    Child,
+    /// Exactly this set of children failing together
+    Combination,
+    /// A deeper failing descendant, see `crate::processor::FailureOrigins`
+    Origin,
+}
+
+/// One candidate explanation of a parent span's failures
+#[derive(Serialize, Clone, Debug)]
+pub struct RootCause {
+    pub kind: CauseKind,
+    pub spans: Vec<String>,
+    /// Executions of the parent where the candidate failed
+    pub executions: usize,
+    /// Those of them where the parent failed too
+    pub failures: usize,
+    /// P(parent fails | candidate failed)
+    pub fail_prob: f64,
+    /// fail_prob over the parent's overall failure rate
+    pub lift: f64,
+    /// Share of the parent's failures that came with the candidate failing
+    pub contribution: f64,
+}
+
+/// Ranked root-cause candidates of one parent span
+#[derive(Serialize, Clone, Debug)]
+pub struct RootCauseReport {
+    pub span: String,
+    pub executions: usize,
+    pub failures: usize,
+    /// Overall failure rate, from the parent's __TOTAL__ counts
+    pub baseline: f64,
+    /// Share of the parent's failures with no child failing at all
+    pub unexplained: f64,
+    /// Largest contribution first
+    pub causes: Vec<RootCause>,
+}
+
+
+/// Rank children, combinations of children and failure origins of every
+/// failing span by their contribution to its failures
+pub fn analyze_root_causes(summary: &DependencySummary) -> Vec<RootCauseReport> {
+    let mut reports: Vec<RootCauseReport> = summary.fail_bernoulli.iter()
+        .filter(|(_, fail_summary)| fail_summary.total.0 > 0)
+        .map(|(span, fail_summary)| {
+            let (failures, executions) = fail_summary.total;
+            let baseline = failures as f64 / executions as f64;
+            let cause = |kind, spans: Vec<String>, (cause_failures, cause_executions): BernSummary| {
+                let fail_prob = cause_failures as f64 / cause_executions as f64;
+                RootCause {
+                    kind,
+                    spans,
+                    executions: cause_executions,
+                    failures: cause_failures,
+                    fail_prob,
+                    lift: fail_prob / baseline,
+                    contribution: cause_failures as f64 / failures as f64,
+                }
+            };
+
+            let mut children: BTreeMap<&String, BernSummary> = BTreeMap::new();
+            let mut causes = Vec::new();
+            let mut unexplained = 0;
+            for (failing_subspans, counts) in fail_summary.failing_subspans.iter() {
+                if counts.1 == 0 {
+                    continue;
+                }
+                if failing_subspans.is_empty() {
+                    unexplained += counts.0;
+                    continue;
+                }
+                for child in failing_subspans.iter() {
+                    let child_counts = children.entry(child).or_default();
+                    child_counts.0 += counts.0;
+                    child_counts.1 += counts.1;
+                }
+                if failing_subspans.len() > 1 {
+                    causes.push(cause(CauseKind::Combination, spans_of(failing_subspans), *counts));
+                }
+            }
+            for (origin, counts) in summary.failure_origins.get(span).into_iter().flatten() {
+                // an origin right below the span says no more than the child does
+                if counts.1 > 0 && children.get(origin) != Some(counts) {
+                    causes.push(cause(CauseKind::Origin, vec![origin.clone()], *counts));
+                }
+            }
+            for (child, counts) in children {
+                causes.push(cause(CauseKind::Child, vec![child.clone()], counts));
+            }
+            causes.sort_by(|a, b| {
+                b.contribution.total_cmp(&a.contribution)
+                    .then(b.lift.total_cmp(&a.lift))
+                    .then(a.kind.cmp(&b.kind))
+                    .then(a.spans.cmp(&b.spans))
+            });
+
+            RootCauseReport {
+                span: span.clone(),
+                executions,
+                failures,
+                baseline,
+                unexplained: unexplained as f64 / failures as f64,
+                causes,
+            }
+        })
+        .collect();
+    reports.sort_by(|a, b| b.failures.cmp(&a.failures).then(a.span.cmp(&b.span)));
+    reports
+}
+
+fn spans_of(failing_subspans: &SubspanSet) -> Vec<String> {
+    failing_subspans.iter().cloned().collect()
+}
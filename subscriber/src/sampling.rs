@@ -0,0 +1,175 @@
+use rand::Rng;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::processor::Observation;
+
+
+/// Which traces `DependencyLayer` records
+///
+/// Head sampling decides when a root span is created and skips the whole
+/// subtree otherwise. Tail sampling buffers a trace until its root closes,
+/// always keeps it if any span failed, and keeps successful ones with
+/// probability `tail`. Kept observations are weighted by the inverse of
+/// their sampling probability, so rates and transition probabilities stay
+/// unbiased.
+#[derive(Clone, Copy, Debug)]
+pub struct Sampling {
+    head: f64,
+    tail: f64,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            head: 1.0,
+            tail: 1.0,
+        }
+    }
+}
+
+impl Sampling {
+    /// Record every trace
+    pub fn always() -> Self {
+        Self::default()
+    }
+
+    /// Record a trace with probability `head`, decided at its root span
+    pub fn head(head: f64) -> Self {
+        Self::default().with_head(head)
+    }
+
+    /// Record every failing trace, and successful ones with probability `tail`
+    pub fn tail(tail: f64) -> Self {
+        Self::default().with_tail(tail)
+    }
+
+    pub fn with_head(mut self, head: f64) -> Self {
+        self.head = head.clamp(f64::MIN_POSITIVE, 1.0);
+        self
+    }
+
+    pub fn with_tail(mut self, tail: f64) -> Self {
+        self.tail = tail.clamp(f64::MIN_POSITIVE, 1.0);
+        self
+    }
+
+    pub fn is_head_sampled(&self) -> bool {
+        self.head < 1.0
+    }
+
+    pub fn is_tail_sampled(&self) -> bool {
+        self.tail < 1.0
+    }
+
+    /// Weight of a new trace, or none if it should be skipped
+    pub fn sample_head(&self) -> Option<f64> {
+        if !self.is_head_sampled() {
+            Some(1.0)
+        } else if rand::thread_rng().gen_bool(self.head) {
+            Some(1.0 / self.head)
+        } else {
+            None
+        }
+    }
+
+    /// Extra weight of a finished trace, or none if it should be dropped
+    pub fn sample_tail(&self, failing: bool) -> Option<f64> {
+        if failing || !self.is_tail_sampled() {
+            Some(1.0)
+        } else if rand::thread_rng().gen_bool(self.tail) {
+            Some(1.0 / self.tail)
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Observations a trace buffers before its tail draw is taken early
+pub const MAX_TRACE_OBSERVATIONS: usize = 4096;
+
+/// Outcome of a trace's tail draw
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TailDecision {
+    /// Not drawn yet, observations are buffered
+    Pending,
+    /// Observations are applied with this weight
+    Keep(f64),
+    Drop,
+}
+
+/// Observations of a trace held back until its root span closes
+///
+/// A trace outgrowing `MAX_TRACE_OBSERVATIONS` draws early, from whether it
+/// failed so far, and then passes its observations through or drops them.
+/// Failures after an early draw are kept only as often as successes, which
+/// leaves the weighted counts unbiased.
+#[derive(Debug)]
+pub struct TraceBuffer {
+    pub observations: Vec<Observation>,
+    pub failing: bool,
+    decision: TailDecision,
+    sampling: Sampling,
+    /// Head sampling weight of the trace
+    weight: f64,
+}
+
+impl TraceBuffer {
+    pub fn new(sampling: Sampling, weight: f64) -> Self {
+        TraceBuffer {
+            observations: Vec::new(),
+            failing: false,
+            decision: TailDecision::Pending,
+            sampling,
+            weight,
+        }
+    }
+
+    pub fn decision(&self) -> TailDecision {
+        self.decision
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.observations.len() >= MAX_TRACE_OBSERVATIONS
+    }
+
+    /// Take the tail draw unless already taken, dropping the buffer if it loses
+    pub fn decide(&mut self) -> TailDecision {
+        if self.decision == TailDecision::Pending {
+            self.decision = match self.sampling.sample_tail(self.failing) {
+                Some(weight) => TailDecision::Keep(self.weight * weight),
+                None => TailDecision::Drop,
+            };
+        }
+        if self.decision == TailDecision::Drop {
+            self.observations.clear();
+        }
+        self.decision
+    }
+}
+
+pub type TraceHandle = Arc<Mutex<TraceBuffer>>;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_traces_are_kept_at_head_weight() {
+        let mut buffer = TraceBuffer::new(Sampling::head(0.5).with_tail(0.01), 2.0);
+        buffer.failing = true;
+        assert_eq!(buffer.decide(), TailDecision::Keep(2.0));
+        // later draws keep the first outcome
+        buffer.failing = false;
+        assert_eq!(buffer.decide(), TailDecision::Keep(2.0));
+    }
+
+    #[test]
+    fn unsampled_tails_keep_everything() {
+        let mut buffer = TraceBuffer::new(Sampling::always(), 1.0);
+        assert_eq!(buffer.decision(), TailDecision::Pending);
+        assert_eq!(buffer.decide(), TailDecision::Keep(1.0));
+    }
+}
@@ -0,0 +1,311 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use crate::analysis;
+use crate::markov::ContextMarkovSummary;
+use crate::markov::StateSummary;
+use crate::processor::DependencySummary;
+use crate::processor::FailBernoulliSummary;
+use crate::processor::FAILURE_STATE;
+use crate::processor::INITIAL_STATE;
+use crate::processor::SUCCESS_STATE;
+use crate::record::ROOT_SPAN;
+
+
+const SENSITIVITY_STEP: f64 = 1e-4;
+
+
+/// How much a root span's reliability depends on one of its descendants
+#[derive(Serialize, Clone, Debug)]
+pub struct DependencySensitivity {
+    pub root: String,
+    pub dependency: String,
+    /// Failure probability of the root span under the current model
+    pub root_fail_prob: f64,
+    /// Failure probability of the dependency under the current model
+    pub fail_prob: f64,
+    /// Finite-difference estimate of d(root_fail_prob) / d(fail_prob)
+    pub derivative: f64,
+    /// Drop in root_fail_prob if the dependency never failed
+    pub fixed_improvement: f64,
+}
+
+
+/*
+ * What-if reliability model over nested Markov chains
+ *
+ *  A span with a chain fails with the probability of reaching
+ *  __FAILURE_STATE__ from __INITIAL_STATE__. A span without a chain fails
+ *  with its observed rate. Changing a child's failure probability scales
+ *  the child's transition into __FAILURE_STATE__ by the same ratio and
+ *  renormalizes its remaining transitions, then propagates upward. A child
+ *  that never failed has nothing to scale, so its new failures are assumed
+ *  to fail the parent right away.
+ */
+pub struct ReliabilityModel<'a> {
+    span_markov: &'a ContextMarkovSummary<String>,
+    fail_bernoulli: &'a FailBernoulliSummary,
+    baseline: BTreeMap<String, f64>,
+}
+
+impl<'a> ReliabilityModel<'a> {
+    pub fn new(summary: &'a DependencySummary) -> Self {
+        Self::from_parts(&summary.span_markov, &summary.fail_bernoulli)
+    }
+
+    pub fn from_parts(
+        span_markov: &'a ContextMarkovSummary<String>,
+        fail_bernoulli: &'a FailBernoulliSummary,
+    ) -> Self {
+        let mut model = ReliabilityModel {
+            span_markov,
+            fail_bernoulli,
+            baseline: BTreeMap::new(),
+        };
+        let mut baseline = BTreeMap::new();
+        for span in model.all_spans() {
+            model.fail_prob_inner(&span, &BTreeMap::new(), &mut baseline, &mut Vec::new());
+        }
+        model.baseline = baseline;
+        model
+    }
+
+    /// Spans directly under __ROOT_SPAN__
+    pub fn roots(&self) -> BTreeSet<String> {
+        self.span_markov.get(ROOT_SPAN)
+            .map(analysis::child_spans)
+            .unwrap_or_default()
+    }
+
+    /// Every span below `span` across nested chains, excluding itself
+    pub fn descendants(&self, span: &str) -> BTreeSet<String> {
+        let mut seen = BTreeSet::new();
+        let mut frontier = vec![span.to_string()];
+        while let Some(current) = frontier.pop() {
+            if let Some(chain) = self.span_markov.get(&current) {
+                for child in analysis::child_spans(chain) {
+                    if seen.insert(child.clone()) {
+                        frontier.push(child);
+                    }
+                }
+            }
+        }
+        seen.remove(span);
+        seen
+    }
+
+    /// Failure probability of `span`, with some spans' probabilities overridden
+    pub fn fail_prob(&self, span: &str, overrides: &BTreeMap<String, f64>) -> f64 {
+        if overrides.is_empty() {
+            if let Some(prob) = self.baseline.get(span) {
+                return *prob;
+            }
+        }
+        self.fail_prob_inner(span, overrides, &mut BTreeMap::new(), &mut Vec::new())
+    }
+
+    /// Sensitivity of every root span to every one of its descendants,
+    /// ranked by how much fixing the dependency would improve the root
+    pub fn sensitivity(&self) -> Vec<DependencySensitivity> {
+        let mut sensitivities = Vec::new();
+        for root in self.roots() {
+            let root_fail_prob = self.fail_prob(&root, &BTreeMap::new());
+            for dependency in self.descendants(&root) {
+                let fail_prob = self.fail_prob(&dependency, &BTreeMap::new());
+                let lo = (fail_prob - SENSITIVITY_STEP).max(0.0);
+                let hi = (fail_prob + SENSITIVITY_STEP).min(1.0);
+                let derivative = (
+                    self.fail_prob(&root, &BTreeMap::from([(dependency.clone(), hi)]))
+                    - self.fail_prob(&root, &BTreeMap::from([(dependency.clone(), lo)]))
+                ) / (hi - lo);
+                let fixed_improvement = root_fail_prob
+                    - self.fail_prob(&root, &BTreeMap::from([(dependency.clone(), 0.0)]));
+                sensitivities.push(DependencySensitivity {
+                    root: root.clone(),
+                    dependency,
+                    root_fail_prob,
+                    fail_prob,
+                    derivative,
+                    fixed_improvement,
+                });
+            }
+        }
+        sensitivities.sort_by(|a, b| {
+            b.fixed_improvement.total_cmp(&a.fixed_improvement)
+                .then(b.derivative.total_cmp(&a.derivative))
+                .then(a.root.cmp(&b.root))
+                .then(a.dependency.cmp(&b.dependency))
+        });
+        sensitivities
+    }
+
+    fn all_spans(&self) -> BTreeSet<String> {
+        self.span_markov.iter()
+            .flat_map(|(span, chain)| {
+                std::iter::once(span.clone()).chain(analysis::child_spans(chain))
+            })
+            .filter(|span| span != ROOT_SPAN)
+            .collect()
+    }
+
+    fn fail_prob_inner(
+        &self,
+        span: &str,
+        overrides: &BTreeMap<String, f64>,
+        memo: &mut BTreeMap<String, f64>,
+        stack: &mut Vec<String>,
+    ) -> f64 {
+        if let Some(prob) = overrides.get(span) {
+            return *prob;
+        }
+        if let Some(prob) = memo.get(span) {
+            return *prob;
+        }
+        let observed = analysis::fail_rate(self.fail_bernoulli, span).unwrap_or(0.0);
+        let chain = match self.span_markov.get(span) {
+            Some(chain) if chain.contains_key(INITIAL_STATE) => chain,
+            _ => {
+                memo.insert(span.to_string(), observed);
+                return observed;
+            },
+        };
+        if stack.iter().any(|s| s == span) {
+            // recursive span, fall back to what was observed
+            return observed;
+        }
+
+        stack.push(span.to_string());
+        let mut adjusted = chain.clone();
+        for child in analysis::child_spans(chain) {
+            let child_prob = self.fail_prob_inner(&child, overrides, memo, stack);
+            // no baseline yet while computing it, leave the chain as observed
+            let base = match self.baseline.get(&child) {
+                Some(base) => *base,
+                None => continue,
+            };
+            for (state, edges) in adjusted.iter_mut() {
+                if !analysis::state_spans(state).contains(&child.as_str()) {
+                    continue;
+                }
+                let fail = edges.get(FAILURE_STATE).copied().unwrap_or(0.0);
+                let new_fail = if base > 0.0 {
+                    fail * child_prob / base
+                } else {
+                    fail + child_prob * (1.0 - fail)
+                };
+                set_failure(edges, new_fail);
+            }
+        }
+        stack.pop();
+
+        let prob = analysis::absorption_probs(&adjusted, FAILURE_STATE)
+            .get(INITIAL_STATE)
+            .copied()
+            .unwrap_or(observed);
+        memo.insert(span.to_string(), prob);
+        prob
+    }
+}
+
+/// Set the transition into __FAILURE_STATE__ and renormalize the others
+fn set_failure(edges: &mut StateSummary<String>, new_fail: f64) {
+    let fail = edges.get(FAILURE_STATE).copied().unwrap_or(0.0);
+    let new_fail = new_fail.clamp(0.0, 1.0);
+    let rest = 1.0 - fail;
+    if rest > 0.0 {
+        let scale = (1.0 - new_fail) / rest;
+        for (next_state, prob) in edges.iter_mut() {
+            if next_state != FAILURE_STATE {
+                *prob *= scale;
+            }
+        }
+    } else if new_fail < 1.0 {
+        // nothing observed beyond failing, so assume the parent carries on
+        edges.insert(SUCCESS_STATE.to_string(), 1.0 - new_fail);
+    }
+    if fail > 0.0 || new_fail > 0.0 {
+        edges.insert(FAILURE_STATE.to_string(), new_fail);
+    }
+}
+
+/// Rank every root span's dependencies by how much fixing them would help
+pub fn analyze_sensitivity(summary: &DependencySummary) -> Vec<DependencySensitivity> {
+    ReliabilityModel::new(summary).sensitivity()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov::MarkovSummary;
+    use crate::processor::FailSummary;
+
+    fn chain(edges: &[(&str, &str, f64)]) -> MarkovSummary<String> {
+        let mut chain = MarkovSummary::new();
+        for (state, next_state, prob) in edges {
+            chain.entry(state.to_string()).or_insert_with(StateSummary::new).insert(next_state.to_string(), *prob);
+        }
+        chain
+    }
+
+    fn fails(failures: usize, executions: usize) -> FailSummary {
+        FailSummary {
+            total: (failures, executions),
+            failing_subspans: Vec::new(),
+            samples: (failures, executions),
+        }
+    }
+
+    /* call_a runs call_c then call_b, and fails half the times call_b fails */
+    fn parts() -> (ContextMarkovSummary<String>, FailBernoulliSummary) {
+        let span_markov = ContextMarkovSummary::from([
+            (ROOT_SPAN.to_string(), chain(&[(INITIAL_STATE, "call_a", 1.0), ("call_a", SUCCESS_STATE, 1.0)])),
+            ("call_a".to_string(), chain(&[
+                (INITIAL_STATE, "call_c", 1.0),
+                ("call_c", "call_b", 1.0),
+                ("call_b", FAILURE_STATE, 0.5),
+                ("call_b", SUCCESS_STATE, 0.5),
+            ])),
+        ]);
+        let fail_bernoulli = FailBernoulliSummary::from([
+            ("call_a".to_string(), fails(5, 10)),
+            ("call_b".to_string(), fails(1, 10)),
+            ("call_c".to_string(), fails(0, 10)),
+        ]);
+        (span_markov, fail_bernoulli)
+    }
+
+    #[test]
+    fn fail_prob_follows_the_chain() {
+        let (span_markov, fail_bernoulli) = parts();
+        let model = ReliabilityModel::from_parts(&span_markov, &fail_bernoulli);
+        assert!((model.fail_prob("call_a", &BTreeMap::new()) - 0.5).abs() < 1e-9);
+        assert!((model.fail_prob("call_b", &BTreeMap::new()) - 0.1).abs() < 1e-9);
+        let fixed = BTreeMap::from([("call_b".to_string(), 0.0)]);
+        assert!(model.fail_prob("call_a", &fixed).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sensitivity_scales_failing_children() {
+        let (span_markov, fail_bernoulli) = parts();
+        let sensitivities = ReliabilityModel::from_parts(&span_markov, &fail_bernoulli).sensitivity();
+        let call_b = sensitivities.iter().find(|s| s.dependency == "call_b").unwrap();
+        assert_eq!(call_b.root, "call_a");
+        // failures of call_a scale with those of call_b: 0.5 / 0.1
+        assert!((call_b.derivative - 5.0).abs() < 1e-6);
+        assert!((call_b.fixed_improvement - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sensitivity_to_never_failing_children() {
+        let (span_markov, fail_bernoulli) = parts();
+        let model = ReliabilityModel::from_parts(&span_markov, &fail_bernoulli);
+        // call_c failing with q fails call_a right away: q + (1 - q) * 0.5
+        let broken = BTreeMap::from([("call_c".to_string(), 0.2)]);
+        assert!((model.fail_prob("call_a", &broken) - 0.6).abs() < 1e-9);
+        let call_c = model.sensitivity().into_iter().find(|s| s.dependency == "call_c").unwrap();
+        assert!((call_c.derivative - 0.5).abs() < 1e-6);
+        assert_eq!(call_c.fixed_improvement, 0.0);
+    }
+}
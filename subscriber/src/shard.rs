@@ -0,0 +1,54 @@
+use std::sync::Mutex;
+use thread_local::ThreadLocal;
+
+use crate::estimator::Estimator;
+
+
+/// An estimator sharded per thread, double-buffered against an accumulated total
+///
+/// Observing locks only the calling thread's shard. Summaries swap every
+/// shard out for an empty one, holding each shard lock only for the swap,
+/// and merge the drained deltas into the total outside the hot path.
+#[derive(Debug)]
+pub struct Sharded<E: Estimator> {
+    shards: ThreadLocal<Mutex<E>>,
+    total: Mutex<E>,
+}
+
+impl<E: Estimator> Default for Sharded<E> {
+    fn default() -> Self {
+        Sharded {
+            shards: ThreadLocal::new(),
+            total: Mutex::new(E::default()),
+        }
+    }
+}
+
+impl<E: Estimator> Sharded<E> {
+    pub fn observe(&self, observation: E::Observation, weight: f64) {
+        self.shards.get_or_default().lock().unwrap().observe(observation, weight)
+    }
+
+    /// Summary of everything observed so far, leaving the counts in place
+    pub fn summarize(&self) -> E::Summary {
+        self.with_total(E::summarize)
+    }
+
+    /// Look into everything observed so far, beyond what summaries keep
+    pub fn with_total<R, F: FnOnce(&E) -> R>(&self, f: F) -> R {
+        let mut total = self.total.lock().unwrap();
+        for shard in self.shards.iter() {
+            let delta = std::mem::take(&mut *shard.lock().unwrap());
+            total.merge(delta);
+        }
+        f(&total)
+    }
+
+    pub fn reset(&self) {
+        let mut total = self.total.lock().unwrap();
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().reset();
+        }
+        total.reset();
+    }
+}
@@ -0,0 +1,259 @@
+/* Significance tests shared by the summary comparisons */
+
+
+/// p-value under which the summary comparisons call a difference significant
+pub const DEFAULT_SIGNIFICANCE: f64 = 1e-3;
+
+const SERIES_ITERATIONS: usize = 1000;
+const SERIES_TOLERANCE: f64 = 1e-14;
+const TINY: f64 = 1e-300;
+/* Past this many tables to sum, fisher_exact falls back to chi-square */
+const FISHER_EXACT_TABLES: usize = 10_000;
+
+
+/// ln Γ(x) for x > 0, Lanczos approximation
+pub fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let mut series = 1.000000000190015;
+    for (idx, coefficient) in COEFFICIENTS.iter().enumerate() {
+        series += coefficient / (x + 1.0 + idx as f64);
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+/// Upper regularized incomplete gamma Q(a, x)
+pub fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let ln_prefix = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1.0 {
+        // series for P(a, x)
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        for n in 1 .. SERIES_ITERATIONS {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * SERIES_TOLERANCE {
+                break;
+            }
+        }
+        (1.0 - sum * ln_prefix.exp()).clamp(0.0, 1.0)
+    } else {
+        // Lentz's continued fraction for Q(a, x)
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / TINY;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for n in 1 .. SERIES_ITERATIONS {
+            let an = -(n as f64) * (n as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = b + an / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < SERIES_TOLERANCE {
+                break;
+            }
+        }
+        (ln_prefix.exp() * h).clamp(0.0, 1.0)
+    }
+}
+
+/// P(X >= x) for X chi-square distributed with `dof` degrees of freedom
+pub fn chi_square_sf(x: f64, dof: usize) -> f64 {
+    if dof == 0 {
+        return 1.0;
+    }
+    gamma_q(dof as f64 / 2.0, x / 2.0)
+}
+
+/// Chi-square test that two samples of counts over the same categories come
+/// from the same distribution, as (statistic, degrees of freedom, p-value)
+///
+/// Categories empty in both samples are left out.
+pub fn chi_square_homogeneity(lhs: &[f64], rhs: &[f64]) -> (f64, usize, f64) {
+    let lhs_total: f64 = lhs.iter().sum();
+    let rhs_total: f64 = rhs.iter().sum();
+    let total = lhs_total + rhs_total;
+    if lhs_total <= 0.0 || rhs_total <= 0.0 {
+        return (0.0, 0, 1.0);
+    }
+    let mut statistic = 0.0;
+    let mut categories: usize = 0;
+    for (lhs_count, rhs_count) in lhs.iter().zip(rhs.iter()) {
+        let column = lhs_count + rhs_count;
+        if column <= 0.0 {
+            continue;
+        }
+        categories += 1;
+        let lhs_expected = lhs_total * column / total;
+        let rhs_expected = rhs_total * column / total;
+        statistic += (lhs_count - lhs_expected).powi(2) / lhs_expected;
+        statistic += (rhs_count - rhs_expected).powi(2) / rhs_expected;
+    }
+    let dof = categories.saturating_sub(1);
+    (statistic, dof, chi_square_sf(statistic, dof))
+}
+
+/// Two-sided exact binomial test of `successes` out of `trials` against `prob`,
+/// doubling the tail on the side of `successes`
+pub fn binomial_test(successes: usize, trials: usize, prob: f64) -> f64 {
+    if trials == 0 {
+        return 1.0;
+    }
+    let prob = prob.clamp(0.0, 1.0);
+    if prob == 0.0 || prob == 1.0 {
+        let expected = if prob == 0.0 { 0 } else { trials };
+        return if successes == expected { 1.0 } else { 0.0 };
+    }
+    let upper = successes as f64 >= trials as f64 * prob;
+    (2.0 * binomial_tail(successes, trials, prob, upper)).min(1.0)
+}
+
+/* P(X >= k) if upper, P(X <= k) otherwise, summing outward from k */
+fn binomial_tail(k: usize, n: usize, p: f64, upper: bool) -> f64 {
+    let ln_pmf = |i: usize| {
+        ln_gamma(n as f64 + 1.0) - ln_gamma(i as f64 + 1.0) - ln_gamma((n - i) as f64 + 1.0)
+            + i as f64 * p.ln() + (n - i) as f64 * (1.0 - p).ln()
+    };
+    let mean = n as f64 * p;
+    let mut sum = 0.0;
+    let mut i = k;
+    loop {
+        let term = ln_pmf(i).exp();
+        sum += term;
+        // terms past the mode only shrink, stop once they stop mattering
+        let past_mode = if upper { i as f64 > mean } else { (i as f64) < mean };
+        if past_mode && term <= sum * SERIES_TOLERANCE {
+            break;
+        }
+        match upper {
+            true if i < n => i += 1,
+            false if i > 0 => i -= 1,
+            _ => break,
+        }
+    }
+    sum.min(1.0)
+}
+
+/// Two-sided Fisher exact test of a 2x2 table `[[a, b], [c, d]]`, summing
+/// the tables with the same margins no more likely than the observed one
+///
+/// Large tables, where chi-square holds up and summing would take long,
+/// get a chi-square test of homogeneity instead.
+pub fn fisher_exact(a: usize, b: usize, c: usize, d: usize) -> f64 {
+    let (row, other_row, column) = (a + b, c + d, a + c);
+    let tables = row.min(column) + 1 - column.saturating_sub(other_row);
+    if tables > FISHER_EXACT_TABLES {
+        return chi_square_homogeneity(&[a as f64, b as f64], &[c as f64, d as f64]).2;
+    }
+    let ln_choose = |n: usize, k: usize| {
+        ln_gamma(n as f64 + 1.0) - ln_gamma(k as f64 + 1.0) - ln_gamma((n - k) as f64 + 1.0)
+    };
+    let ln_denominator = ln_choose(row + other_row, column);
+    let ln_pmf = |x: usize| ln_choose(row, x) + ln_choose(other_row, column - x) - ln_denominator;
+    let observed = ln_pmf(a);
+    let mut p_value = 0.0;
+    for x in column.saturating_sub(other_row) ..= row.min(column) {
+        let ln_prob = ln_pmf(x);
+        // tolerance for tables as likely as the observed one up to rounding
+        if ln_prob <= observed + 1e-7 {
+            p_value += ln_prob.exp();
+        }
+    }
+    p_value.min(1.0)
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the order given, controlling the
+/// false discovery rate over all of the tests
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let tests = p_values.len();
+    let mut order: Vec<usize> = (0 .. tests).collect();
+    order.sort_by(|lhs, rhs| p_values[*lhs].total_cmp(&p_values[*rhs]));
+    let mut adjusted = vec![1.0; tests];
+    let mut running_min: f64 = 1.0;
+    for (rank, idx) in order.into_iter().enumerate().rev() {
+        running_min = running_min.min(p_values[idx] * tests as f64 / (rank + 1) as f64);
+        adjusted[idx] = running_min;
+    }
+    adjusted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn gamma_q_matches_known_values() {
+        // Q(1, x) = exp(-x)
+        assert_close(gamma_q(1.0, 2.0), (-2.0f64).exp(), 1e-10);
+        assert_close(gamma_q(0.5, 1.92), 0.05004, 1e-4);
+        assert_close(gamma_q(2.0, 5.0), 0.04043, 1e-4);
+        assert_eq!(gamma_q(3.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn chi_square_sf_matches_known_values() {
+        assert_close(chi_square_sf(3.84, 1), 0.05004, 1e-4);
+        assert_close(chi_square_sf(10.0, 4), 0.04043, 1e-4);
+        assert_eq!(chi_square_sf(10.0, 0), 1.0);
+    }
+
+    #[test]
+    fn binomial_test_doubles_the_tail() {
+        assert_close(binomial_test(30, 100, 0.2), 0.0225, 5e-4);
+        assert_eq!(binomial_test(20, 100, 0.2), 1.0);
+        assert_eq!(binomial_test(0, 10, 0.0), 1.0);
+        assert_eq!(binomial_test(1, 10, 0.0), 0.0);
+    }
+
+    #[test]
+    fn chi_square_homogeneity_matches_known_values() {
+        let (statistic, dof, p_value) = chi_square_homogeneity(&[30.0, 70.0], &[50.0, 50.0]);
+        assert_close(statistic, 8.333, 1e-3);
+        assert_eq!(dof, 1);
+        assert_close(p_value, 0.00389, 1e-5);
+        // categories empty on both sides do not count
+        assert_eq!(chi_square_homogeneity(&[30.0, 70.0, 0.0], &[50.0, 50.0, 0.0]).1, 1);
+    }
+
+    #[test]
+    fn fisher_exact_matches_known_values() {
+        assert_close(fisher_exact(3, 1, 1, 3), 34.0 / 70.0, 1e-9);
+        assert_close(fisher_exact(8, 2, 1, 5), 0.034965, 1e-6);
+        assert_close(fisher_exact(30, 70, 50, 50), 0.0059373, 1e-6);
+        assert_close(fisher_exact(5, 95, 6, 94), 1.0, 1e-9);
+        assert_eq!(fisher_exact(0, 0, 0, 0), 1.0);
+        // summing would take 11801 tables, the exact p-value is about 0.0291
+        assert_close(fisher_exact(6000, 14000, 5800, 14200), 0.0291, 1e-3);
+    }
+
+    #[test]
+    fn benjamini_hochberg_keeps_order() {
+        let adjusted = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.005]);
+        for (actual, expected) in adjusted.into_iter().zip([0.02, 0.04, 0.04, 0.02]) {
+            assert_close(actual, expected, 1e-12);
+        }
+        assert!(benjamini_hochberg(&[]).is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use serde::Serialize;
+use std::fmt::Write;
+
+use crate::analysis;
+use crate::processor::DependencySummary;
+use crate::processor::INITIAL_STATE;
+use crate::record::ROOT_SPAN;
+
+
+/// A span in the call tree stitched together from every parent's chain
+#[derive(Serialize, Clone, Debug)]
+pub struct CallTreeNode {
+    pub name: String,
+    /// Expected number of calls per call of the parent span
+    pub multiplicity: f64,
+    /// Expected number of calls per call of the tree's root
+    pub total_multiplicity: f64,
+    pub fail_rate: Option<f64>,
+    /// Already an ancestor of this node, so not expanded again
+    pub recursive: bool,
+    pub children: Vec<CallTreeNode>,
+}
+
+impl CallTreeNode {
+    /// Indented text rendering, one span per line
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", self.name).unwrap();
+        self.render_children(&mut out, "");
+        out
+    }
+
+    fn render_children(&self, out: &mut String, prefix: &str) {
+        for (idx, child) in self.children.iter().enumerate() {
+            let last = idx + 1 == self.children.len();
+            let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+            write!(out, "{}{}{}  x{:.2}", prefix, branch, child.name, child.multiplicity).unwrap();
+            if let Some(fail_rate) = child.fail_rate {
+                write!(out, "  fail {:.2}%", 100.0 * fail_rate).unwrap();
+            }
+            if child.recursive {
+                write!(out, "  (recursive)").unwrap();
+            }
+            writeln!(out).unwrap();
+            child.render_children(out, &format!("{}{}", prefix, indent));
+        }
+    }
+}
+
+
+/// Call tree from __ROOT_SPAN__ down through every nested chain
+pub fn build_call_tree(summary: &DependencySummary) -> CallTreeNode {
+    build_call_tree_from(summary, ROOT_SPAN)
+}
+
+/// Call tree below an arbitrary span
+pub fn build_call_tree_from(summary: &DependencySummary, span: &str) -> CallTreeNode {
+    let mut ancestors = Vec::new();
+    build_node(summary, span, 1.0, 1.0, &mut ancestors)
+}
+
+fn build_node(
+    summary: &DependencySummary,
+    span: &str,
+    multiplicity: f64,
+    total_multiplicity: f64,
+    ancestors: &mut Vec<String>,
+) -> CallTreeNode {
+    let mut node = CallTreeNode {
+        name: span.to_string(),
+        multiplicity,
+        total_multiplicity,
+        fail_rate: analysis::fail_rate(&summary.fail_bernoulli, span),
+        recursive: ancestors.iter().any(|ancestor| ancestor == span),
+        children: Vec::new(),
+    };
+    let chain = match summary.span_markov.get(span) {
+        Some(chain) if !node.recursive => chain,
+        _ => return node,
+    };
+
+    // root spans never terminate their chain, so count them once each
+    let visits = if span == ROOT_SPAN {
+        None
+    } else {
+        Some(analysis::span_visits(
+            analysis::expected_visits(chain, INITIAL_STATE),
+            summary.fan_out.get(span),
+        ))
+    };
+    ancestors.push(span.to_string());
+    for child in analysis::child_spans(chain) {
+        let child_multiplicity = visits.as_ref()
+            .and_then(|visits| visits.get(&child).copied())
+            .unwrap_or(1.0);
+        node.children.push(build_node(
+            summary,
+            &child,
+            child_multiplicity,
+            total_multiplicity * child_multiplicity,
+            ancestors,
+        ));
+    }
+    ancestors.pop();
+    node
+}